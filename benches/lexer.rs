@@ -0,0 +1,121 @@
+//! Times lexing a large template, run with `cargo bench`
+//!
+//! Along with the overall allocations per token, it lists each line of the
+//! page that allocates when lexed on its own, so a rule that starts
+//! allocating shows up by name.
+//!
+//! This uses a plain timing loop rather than the unstable `test` crate, so
+//! it runs on stable rust.
+
+extern crate jade;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use jade::lexer::Lexer;
+
+/// Counts allocations so the bench can report how many each token costs
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// A page using most of the syntax, repeated to make the template
+const PAGE: &str = concat!(
+    "doctype html\n",
+    "html(lang=\"en\")\n",
+    "  head\n",
+    "    title= pageTitle\n",
+    "    script(type='text/javascript').\n",
+    "      if (foo) {\n",
+    "         bar(1 + 5)\n",
+    "      }\n",
+    "  body\n",
+    "    // the main content\n",
+    "    h1#title.big Jade - node template engine\n",
+    "    #container.col\n",
+    "      if youAreUsingJade\n",
+    "        p You are amazing, #{name}!\n",
+    "      else\n",
+    "        p Get on it! \\#{not} #[em really]\n",
+    "      ul\n",
+    "        each item, idx in items\n",
+    "          li(class=idx % 2 ? 'odd' : 'even', data-id=\"item-#{item.id}\")= item.name\n",
+    "      +card('x', [1, 2], {a: 1})(class=\"c\")\n",
+    "      p.\n",
+    "        Jade is a terse and simple\n",
+    "        templating language with a\n",
+    "        strong focus on performance\n",
+    "        and powerful features.\n");
+
+const SIZE: usize = 4 * 1024 * 1024;
+const RUNS: u32 = 3;
+const LINE_REPEATS: usize = 1000;
+
+/// Allocations made lexing `src`, or `None` if it doesn't lex
+fn count_allocations(src: &str) -> Option<usize> {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for tok in Lexer::new(src) {
+        tok.ok()?;
+    }
+    Some(ALLOCATIONS.load(Ordering::Relaxed) - before)
+}
+
+fn main() {
+    let mut src = String::with_capacity(SIZE + PAGE.len());
+    while src.len() < SIZE {
+        src.push_str(PAGE);
+    }
+
+    let mut best = None;
+    let mut tokens = 0;
+    let mut allocations = 0;
+    for _ in 0..RUNS {
+        tokens = 0;
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for tok in Lexer::new(&src) {
+            tok.unwrap();
+            tokens += 1;
+        }
+        let elapsed = start.elapsed();
+        allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        if best.is_none_or(|best| elapsed < best) {
+            best = Some(elapsed);
+        }
+    }
+    let best = best.unwrap();
+    println!("lexed {} tokens from {} bytes in {:?} ({:.1} MB/s)",
+             tokens,
+             src.len(),
+             best,
+             src.len() as f64 / best.as_secs_f64() / (1024.0 * 1024.0));
+    println!("{} allocations ({:.2} per token)",
+             allocations,
+             allocations as f64 / tokens as f64);
+
+    for line in PAGE.lines().map(str::trim) {
+        let src = format!("{}\n", line).repeat(LINE_REPEATS);
+        match count_allocations(&src) {
+            // a few one-off allocations, e.g. growing the lexer's own
+            // buffers, aren't made per line
+            Some(allocations) if allocations >= LINE_REPEATS => println!("  {:.2} per line: {}",
+                                          allocations as f64 / LINE_REPEATS as f64,
+                                          line),
+            _ => {}
+        }
+    }
+}
@@ -1,31 +1,5 @@
 use std::default::Default;
-use regex;
-
-
-/// Vendoring in (with slight modifications to make not-a-method) from the Rust
-/// source to avoid deprecation error.
-fn slice_chars(s: &str, begin: usize, end: usize) -> &str {
-    assert!(begin <= end);
-    let mut count = 0;
-    let mut begin_byte = None;
-    let mut end_byte = None;
-
-    // This could be even more efficient by not decoding,
-    // only finding the char boundaries
-    for (idx, _) in s.char_indices() {
-        if count == begin { begin_byte = Some(idx); }
-        if count == end { end_byte = Some(idx); break; }
-        count += 1;
-    }
-    if begin_byte.is_none() && count == begin { begin_byte = Some(s.len()) }
-    if end_byte.is_none() && count == end { end_byte = Some(s.len()) }
 
-    match (begin_byte, end_byte) {
-        (None, _) => panic!("slice_chars: `begin` is beyond end of string"),
-        (_, None) => panic!("slice_chars: `end` is beyond end of string"),
-        (Some(a), Some(b)) => unsafe { s.slice_unchecked(a, b) }
-    }
-}
 
 /*
  * This module is for parsing javascript, with probably more generality
@@ -58,9 +32,11 @@ pub struct BracketState {
 
     // private
 
-    history: String, // Our history is reversed wrt the original (stack)
+    history: History,
+    // the history before the last char, so a `/` starting a comment can
+    // be taken back out of it
+    last_history: History,
     last_char: Option<char>,
-    src: String,
     regexp_start: bool
 }
 
@@ -83,9 +59,9 @@ impl Default for BracketState {
             curly_depth: 0,
             square_depth: 0,
 
-            history: String::new(),
+            history: Default::default(),
+            last_history: Default::default(),
             last_char: None,
-            src: String::new(),
             regexp_start: false
         }
     }
@@ -126,13 +102,13 @@ impl BracketState {
 /// Contains a block of text contained in brackets
 #[derive(PartialEq, Debug)]
 pub struct BracketBlock<'a> {
-    /// The position in the enclosing string of the start of the block
-    start: usize,
-    /// The position in the enclosing string of the end of the block
-    end: usize,
+    /// The byte offset in the enclosing string of the start of the block
+    pub start: usize,
+    /// The byte offset in the enclosing string of the end of the block
+    pub end: usize,
     /// A view of the enclosing string showing just the block enclosed by
     /// the brackets
-    src: &'a str
+    pub src: &'a str
 }
 
 /// Parse the input and mutate the state object, given the starting state
@@ -171,7 +147,7 @@ pub fn parse_max<'a>(src: &'a str) -> Option<BracketBlock> {
         match char_it.next() {
             Some(ch) => {
                 parse_char_from_state(ch, &mut state);
-                pos += 1;
+                pos += ch.len_utf8();
             },
             None => {
                 return None;
@@ -191,7 +167,7 @@ pub fn parse_max<'a>(src: &'a str) -> Option<BracketBlock> {
 ///
 /// - src - The string to search (haystack)
 /// - delimiter - The string to collect until (needle)
-/// - start - Char to start searching at (essentially discard beginning of src)
+/// - start - Byte offset to start searching at (essentially discard beginning of src)
 /// - line_comments - True to ignore delimiter if found in line comment
 ///
 /// # Example
@@ -203,22 +179,23 @@ pub fn parse_until_with_options<'a>(src: &'a str,
 {
     let mut idx = start;
     let mut state: BracketState = Default::default();
+    // walk the chars rather than counting them each time, as `src` may be
+    // the rest of a large template
+    let mut chars = src[start..].chars();
     while state.in_string()
         || state.regexp
         || state.block_comment
         || (!line_comments && state.line_comment)
-        || !starts_with(src, delimiter, idx)
+        || !src[idx..].starts_with(delimiter)
     {
-        if idx + delimiter.chars().count() >= src.chars().count() {
-            return None;
-        }
-        parse_char_from_state(src.chars().nth(idx).unwrap(), &mut state);
-        idx += 1;
+        let ch = chars.next()?;
+        parse_char_from_state(ch, &mut state);
+        idx += ch.len_utf8();
     }
     Some(BracketBlock {
         start: start,
         end: idx,
-        src: slice_chars(src, start, idx),
+        src: &src[start..idx],
     })
 }
 
@@ -231,9 +208,8 @@ pub fn parse_until<'a>(src: &'a str, delimiter: &str) -> Option<BracketBlock<'a>
 
 /// Parse the next character, given a current state
 pub fn parse_char_from_state(ch: char, state: &mut BracketState) {
-    state.src.push(ch);
     let was_comment = state.in_comment();
-    let last_char = peek(&state.history);
+    let last_char = state.history.last;
     //println!("State is {:?}\n char is {:?}", state, ch);
     if state.regexp_start {
         if ch == '/' || ch == '*' {
@@ -275,10 +251,10 @@ pub fn parse_char_from_state(ch: char, state: &mut BracketState) {
             state.escaped = false;
         }
     } else if last_char == Some('/') && ch == '/' {
-        state.history.pop();
+        state.history = state.last_history;
         state.line_comment = true;
     } else if last_char == Some('/') && ch == '*' {
-        state.history.pop();
+        state.history = state.last_history;
         state.block_comment = true;
     } else if ch == '/' && is_regexp(&state.history) {
         state.regexp = true;
@@ -302,6 +278,7 @@ pub fn parse_char_from_state(ch: char, state: &mut BracketState) {
     }
     //println!("{:?}", state);
     if !state.block_comment && !state.line_comment && !was_comment {
+        state.last_history = state.history;
         state.history.push(ch);
     }
 }
@@ -376,60 +353,70 @@ pub fn is_keyword<'a>(src: &'a str) -> bool {
 // Utility
 // =======
 
-/// Check if a string (treated as reversed) is a regex
+/// The longest keyword, `instanceof` or `implements`
+const MAX_KEYWORD_LEN: usize = 10;
+
+/// The end of the source parsed so far, outside of comments
 ///
-/// A regex should match `/.*/`
-// src is reversed, as we access the beginning mostly like a stack.
-// Note that below the comments refer to the opposite end
-// to the code to hopefully aid reading
-fn is_regexp<'a>(src: &'a str) -> bool {
-    // strip beginning whitespace from src,
-    let history = src.trim_right();
-    // then match on the end char
-    match history.chars().rev().next() {
-        // 
-        Some(')') => { false },
-        Some('}') => { true },
-        Some(ch) if is_punctuator(ch) => { true },
-        Some(ch) => {
-            match regex!(r"\b\w+$").captures(history) {
-                Some(capture) => {
-                    match capture.at(0) {
-                        Some(key) if is_keyword(key) => true,
-                        _ => false
-                    }
-                },
-                _ => false
+/// Only as much is kept as `is_regexp` needs, so that parsing doesn't
+/// allocate: the last char, the last char that isn't whitespace, and the
+/// word that ends with it, if it could be a keyword
+#[derive(Clone, Copy, Debug, Default)]
+struct History {
+    last: Option<char>,
+    last_significant: Option<char>,
+    word: [u8; MAX_KEYWORD_LEN],
+    /// The length of `word`, or `None` if it's too long, or not ascii,
+    /// to be a keyword
+    word_len: Option<usize>
+}
+
+impl History {
+    /// Add the next char
+    fn push(&mut self, ch: char) {
+        if !ch.is_whitespace() {
+            let word_char = ch.is_alphanumeric() || ch == '_';
+            let continues_word = self.last.is_some_and(|last| last.is_alphanumeric() || last == '_');
+            if !word_char {
+                self.word_len = Some(0);
+            } else {
+                let len = if continues_word { self.word_len } else { Some(0) };
+                self.word_len = match len {
+                    Some(len) if ch.is_ascii() && len < MAX_KEYWORD_LEN => {
+                        self.word[len] = ch as u8;
+                        Some(len + 1)
+                    },
+                    _ => None
+                };
             }
+            self.last_significant = Some(ch);
         }
-        None => { false }
+        self.last = Some(ch);
     }
 
-}
-
-/// Checks string starts with other string
-#[inline]
-fn starts_with(src: &str, start: &str, i: usize) -> bool {
-    let end = i + start.chars().count();
-    if end >= src.chars().count() {
-        false
-    } else {
-        slice_chars(src, i, i + start.chars().count()) == start
+    /// The word at the end, ignoring any whitespace after it
+    fn word(&self) -> Option<&str> {
+        self.word_len.map(|len| ::std::str::from_utf8(&self.word[..len]).unwrap())
     }
 }
 
-/// Get end char, or None if string is empty
-#[inline]
-fn peek(src: &str) -> Option<char> {
-    match src.char_indices().rev().next() {
-        Some((_, ch)) => { Some(ch) }
-        None => None
+/// Check if a `/` after the history starts a regex
+///
+/// A regex should match `/.*/`
+fn is_regexp(history: &History) -> bool {
+    // match on the last char, ignoring whitespace
+    match history.last_significant {
+        Some(')') => { false },
+        Some('}') => { true },
+        Some(ch) if is_punctuator(ch) => { true },
+        Some(_) => { history.word().is_some_and(is_keyword) },
+        None => { false }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use brackets::{BracketState, BracketBlock, parse, parse_from_state, parse_max, parse_until};
+    use brackets::{BracketState, BracketBlock, parse, parse_from_state, parse_max, parse_until, parse_until_with_options};
 
     #[test]
     fn depth_change_calc() {
@@ -463,6 +450,22 @@ mod tests {
         assert_eq!(block.start, 0);
         assert_eq!(block.end, 17);
         assert_eq!(block.src, "foo.bar(\"%>\").baz");
+
+        let block_option = parse_until("foo.bar()%>", "%>");
+        assert!(block_option.is_some());
+        assert_eq!(block_option.unwrap().src, "foo.bar()");
+    }
+
+    #[test]
+    fn multibyte_positions() {
+        let block = parse_max("'grüße', 名前) rest").unwrap();
+        assert_eq!(block.end, 17);
+        assert_eq!(block.src, "'grüße', 名前");
+
+        let block = parse_until_with_options("ä = '日本%>'%> rest", "%>", 2, false).unwrap();
+        assert_eq!(block.start, 2);
+        assert_eq!(block.end, 15);
+        assert_eq!(block.src, " = '日本%>'");
     }
 
     #[test]
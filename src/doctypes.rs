@@ -0,0 +1,84 @@
+/// The output mode a doctype implies
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Mode {
+    /// html5, e.g. `<input checked>`
+    Terse,
+    /// xml, e.g. `<input checked="checked"/>`
+    Xml,
+    /// Anything else, e.g. xhtml 1.0 `<input checked="checked"/>` but
+    /// `<div></div>`
+    Html
+}
+
+/// Get the full `<!DOCTYPE ...>` string for a doctype, e.g. `html`,
+/// `strict` or `1.1`
+///
+/// Names are matched case insensitively, and any other name is used as a
+/// custom doctype, i.e. `<!DOCTYPE name>`
+pub fn doctype(name: &str) -> String {
+    let full = match &*name.to_lowercase() {
+        "default" | "html" => "<!DOCTYPE html>",
+        "xml" => "<?xml version=\"1.0\" encoding=\"utf-8\" ?>",
+        "transitional" => concat!(
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Transitional//EN\" ",
+            "\"http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd\">"),
+        "strict" => concat!(
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" ",
+            "\"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">"),
+        "frameset" => concat!(
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Frameset//EN\" ",
+            "\"http://www.w3.org/TR/xhtml1/DTD/xhtml1-frameset.dtd\">"),
+        "1.1" => concat!(
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.1//EN\" ",
+            "\"http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd\">"),
+        "basic" => concat!(
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML Basic 1.1//EN\" ",
+            "\"http://www.w3.org/TR/xhtml-basic/xhtml-basic11.dtd\">"),
+        "mobile" => concat!(
+            "<!DOCTYPE html PUBLIC \"-//WAPFORUM//DTD XHTML Mobile 1.2//EN\" ",
+            "\"http://www.openmobilealliance.org/tech/DTD/xhtml-mobile12.dtd\">"),
+        _ => return format!("<!DOCTYPE {}>", name)
+    };
+    full.to_string()
+}
+
+/// Get the output mode for a full doctype string (as returned by `doctype`)
+pub fn mode(doctype: &str) -> Mode {
+    if doctype.to_lowercase() == "<!doctype html>" {
+        Mode::Terse
+    } else if doctype.starts_with("<?xml") {
+        Mode::Xml
+    } else {
+        Mode::Html
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use doctypes::{doctype, mode, Mode};
+
+    #[test]
+    fn shorthands() {
+        assert_eq!(doctype("html"), "<!DOCTYPE html>");
+        assert_eq!(doctype("default"), "<!DOCTYPE html>");
+        assert_eq!(doctype("XML"), "<?xml version=\"1.0\" encoding=\"utf-8\" ?>");
+        assert_eq!(doctype("1.1"), concat!(
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.1//EN\" ",
+            "\"http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd\">"));
+    }
+
+    #[test]
+    fn custom() {
+        assert_eq!(doctype("svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\""),
+                   "<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\">");
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(mode(&doctype("html")), Mode::Terse);
+        assert_eq!(mode("<!doctype HTML>"), Mode::Terse);
+        assert_eq!(mode(&doctype("xml")), Mode::Xml);
+        assert_eq!(mode(&doctype("strict")), Mode::Html);
+        assert_eq!(mode(&doctype("mobile")), Mode::Html);
+    }
+}
@@ -1,6 +1,13 @@
 
 use regex;
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::VecDeque;
+use std::error;
 use std::fmt;
+use std::mem;
+use std::ops::Range;
+use brackets::{self, BracketBlock, BracketState};
 
 /// Represents block types
 #[derive(PartialEq, Debug, Clone)]
@@ -16,9 +23,48 @@ pub enum ValueType {
     String(String)
 }
 
+/// Represents a tag attribute, e.g. `class=foo ? 'a' : 'b'`
+#[derive(PartialEq, Debug, Clone)]
+pub struct Attribute<'a> {
+    /// The attribute name
+    pub name: &'a str,
+    /// The value expression, or `None` for a boolean attribute (`checked`).
+    /// Only owned if interpolations in a string were rewritten
+    pub value: Option<Cow<'a, str>>,
+    /// Whether the value is escaped (`=`) or not (`!=`)
+    pub escaped: bool
+}
+
+impl<'a> Attribute<'a> {
+    /// quick constructor
+    pub fn new(name: &'a str, value: Option<Cow<'a, str>>, escaped: bool) -> Attribute<'a> {
+        Attribute { name, value, escaped }
+    }
+}
+
+/// Represents a filter, e.g. `:coffee(bare=true)`
+#[derive(PartialEq, Debug, Clone)]
+pub struct Filter<'a> {
+    /// The filter name
+    pub name: &'a str,
+    /// The filter options, in the same form as tag attributes
+    pub options: Vec<Attribute<'a>>
+}
+
+impl<'a> Filter<'a> {
+    /// quick constructor
+    pub fn new(name: &'a str, options: Vec<Attribute<'a>>) -> Filter<'a> {
+        Filter { name, options }
+    }
+}
+
 /// Represets token types
+///
+/// Values borrow from the input, except where the lexer has to change
+/// them: text with escaped interpolations, and the dedented body of a
+/// code block
 #[derive(PartialEq, Debug)]
-pub enum TokenType {
+pub enum TokenType<'a> {
     /// Some(Nothing) = no-op, but restart looking
     /// None = carry on looking)
     Nothing,
@@ -28,59 +74,242 @@ pub enum TokenType {
     Indent,
     /// Move out a level
     Outdent,
+    /// Start of a new line
+    Newline,
+    /// Element tag with its name, e.g. `html` or `svg:rect`
+    Tag(&'a str),
+    /// Element tag with an interpolated name at the start of a line,
+    /// e.g. `#{'h' + level}`
+    InterpolatedTag(&'a str),
+    /// Self closing marker `/` after a tag
+    Slash,
+    /// Block expansion `:` after a tag, e.g. `li: a Home`
+    Colon,
+    /// Id shorthand `#container` (on its own, implies a `div`)
+    Id(&'a str),
+    /// Class shorthand `.col` (on its own, implies a `div`)
+    Class(&'a str),
     /// Simple text token
-    Text(String),
+    Text(Cow<'a, str>),
     /// Comment token with contents of comment
     /// (buffer = true <=> render comment in html)
-    Comment(Option<String>, bool), // message, buffer
-    /// TODO not sure :P
-    Interpolation(String),
+    Comment(Option<&'a str>, bool), // message, buffer
+    /// Interpolated expression in text, `#{expr}` (escaped) or `!{expr}`
+    /// (unescaped)
+    Interpolation { expr: &'a str, escaped: bool },
+    /// Code, `= expr` (buffered, escaped), `!= expr` (buffered, unescaped)
+    /// or `- expr` (unbuffered), including `-` followed by an indented block
+    Code { buffered: bool, escaped: bool, expr: Cow<'a, str> },
+    /// Conditional `if expr`
+    If(&'a str),
+    /// Conditional `else if expr`
+    ElseIf(&'a str),
+    /// Conditional `else`
+    Else,
+    /// Negated conditional `unless expr`
+    Unless(&'a str),
+    /// Iteration `each value, key in expr` (or `for ...`), where `key` is
+    /// the index for arrays. It may be followed by an `Else` block, for
+    /// when there is nothing to iterate over
+    Each { value: &'a str, key: Option<&'a str>, expr: &'a str },
+    /// Loop `while expr`
+    While(&'a str),
+    /// Mixin declaration, e.g. `mixin list(id, ...items)`. A rest
+    /// parameter keeps its `...`
+    Mixin { name: &'a str, params: Vec<&'a str> },
+    /// Mixin call, e.g. `+list('x', 1, 2)`. The name of a dynamic call,
+    /// `+#{name}()`, is kept as `#{name}`
+    Call { name: &'a str, args: Vec<&'a str> },
+    /// Filter applied to the following text block, e.g. `:markdown`
+    Filter { name: &'a str, options: Vec<Attribute<'a>> },
+    /// Start of a tag interpolation in text, `#[`. The tokens of the tag
+    /// follow, up to an `EndTagInterpolation`
+    StartTagInterpolation,
+    /// End of a tag interpolation, `]`
+    EndTagInterpolation,
+    /// Start of a pipeless text block (`p.`), whose indented body is
+    /// lexed as `Text`
     PipelessText,
     Yield,
-    Doctype,
-    Case,
-    When,
+    /// Doctype with its value, e.g. `doctype html` (`default` if no
+    /// value is given). See `doctypes` for the full strings
+    Doctype(&'a str),
+    /// Switch `case expr`
+    Case(&'a str),
+    /// Branch of a case, `when expr`. A `When` without a block falls
+    /// through to the next branch
+    When(&'a str),
+    /// Default branch of a case
     Default,
-    Extends,
-    Block{ block_type: BlockType },
+    /// Template inheritance with the path to the parent, `extends layout`
+    Extends(&'a str),
+    /// Named block, e.g. `block content`, `block append scripts` or
+    /// `prepend head`
+    Block{ name: &'a str, block_type: BlockType },
+    /// Mixin block, `block` on its own in a mixin
     MixinBlock,
-    Include(String),
-    Attrs(Vec<String>)
+    /// Include a file, e.g. `include:markdown ./readme.md`, with the
+    /// chain of filters to apply to it. A non-jade file without filters
+    /// is `raw`, and its contents are inserted verbatim. A path without an
+    /// extension is a jade file, as jade adds `.jade` to it
+    Include { path: &'a str, filters: Vec<Filter<'a>>, raw: bool },
+    /// Tag attributes, e.g. `(lang="en", checked)`
+    Attrs(Vec<Attribute<'a>>),
+    /// Attribute spread `&attributes(obj)` with its expression, to be
+    /// merged with any static attributes (concatenating classes)
+    AndAttributes(&'a str)
+}
+
+/// A point in the source
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Position {
+    /// Byte offset from the start of the input
+    pub offset: usize,
+    /// Line number, starting at 1
+    pub line: u32,
+    /// Column in characters, starting at 1
+    pub column: u32
+}
+
+impl Position {
+    /// The position at the start of the input
+    fn start() -> Position {
+        Position { offset: 0, line: 1, column: 1 }
+    }
+
+    /// This position in a part of the input starting at `base`, as a
+    /// position in the whole input
+    fn within(self, base: Position) -> Position {
+        Position {
+            offset: base.offset + self.offset,
+            line: base.line + self.line - 1,
+            column: if self.line == 1 { base.column + self.column - 1 } else { self.column }
+        }
+    }
+
+    /// The position after reading `text` from here
+    fn after(self, text: &str) -> Position {
+        let offset = self.offset + text.len();
+        match text.rfind('\n') {
+            Some(idx) => Position {
+                offset,
+                line: self.line + text.matches('\n').count() as u32,
+                column: text[idx + 1..].chars().count() as u32 + 1
+            },
+            None => Position {
+                offset,
+                line: self.line,
+                column: self.column + text.chars().count() as u32
+            }
+        }
+    }
+}
+
+/// The region of source a token was lexed from, from `start` up to (but
+/// not including) `end`
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position
 }
 
 /// A parsed token from input
 #[derive(PartialEq, Debug)]
-pub struct Token {
-    token_type: TokenType,
+pub struct Token<'a> {
+    token_type: TokenType<'a>,
     line_number: u32,
+    span: Span
 }
 
-impl Token {
-    /// quick constructor
-    pub fn new(token_type: TokenType, line_number: u32) -> Token {
+impl<'a> Token<'a> {
+    /// quick constructor, with an empty span
+    pub fn new(token_type: TokenType<'a>, line_number: u32) -> Token<'a> {
         Token {
             token_type: token_type,
-            line_number: line_number
+            line_number: line_number,
+            span: Span::default()
+        }
+    }
+
+    /// The type of token, with its value
+    #[inline]
+    pub fn token_type(&self) -> &TokenType<'a> {
+        &self.token_type
+    }
+
+    /// The line the token starts on
+    #[inline]
+    pub fn line_number(&self) -> u32 {
+        self.line_number
+    }
+
+    /// Where in the source the token came from
+    ///
+    /// Each part of a line of text has its own span, down to the tokens
+    /// in a tag interpolation. Otherwise the tokens lexed from one piece
+    /// of source share its span, e.g. the `Outdent`s from a single dedent
+    #[inline]
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// The kinds of error found when lexing
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum LexErrorKind {
+    /// Input that can't start any token
+    UnexpectedCharacter,
+    /// A bracketed expression or interpolation that isn't closed
+    UnterminatedBracket,
+    /// Indentation that mixes tabs and spaces, or doesn't line up with
+    /// an enclosing level
+    BadIndentation,
+    /// An attribute or class name that can't be used
+    InvalidAttribute,
+    /// A keyword missing a required part, e.g. `if` with no condition
+    Malformed
+}
+
+/// An error in the input, with where it was found
+#[derive(PartialEq, Debug, Clone)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub message: String,
+    /// The filename given to `Lexer::new_with_filename`
+    pub filename: Option<String>,
+    pub span: Span,
+    /// The line of source the error starts on
+    pub snippet: String
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let start = self.span.start;
+        match self.filename {
+            Some(ref filename) => write!(f, "{}:{}:{}: {}", filename, start.line, start.column, self.message),
+            None => write!(f, "line {}, column {}: {}", start.line, start.column, self.message)
         }
     }
 }
 
+impl error::Error for LexError {}
+
 /// A struct to pass the necessary information to the lexer
 /// from a token matcher method
 #[derive(PartialEq, Debug)]
-struct TokenResult {
+struct TokenResult<'a> {
     /// The matched token
-    token: Token,
+    token: Token<'a>,
     /// The amount of input to consume
     input_increment: usize,
     /// The number of lines to consume
     line_increment: u32
 }
 
-impl TokenResult {
+impl<'a> TokenResult<'a> {
     /// A quick constructor function
     #[inline]
-    fn new(token: Token, input_increment: usize, line_increment: u32) -> TokenResult {
+    fn new(token: Token<'a>, input_increment: usize, line_increment: u32) -> TokenResult<'a> {
         TokenResult {
             token: token,
             input_increment: input_increment,
@@ -89,6 +318,13 @@ impl TokenResult {
     }
 }
 
+/// The result of a token matcher method: a token, `None` if the rule
+/// doesn't match, or an error if it matches invalid input
+type RuleResult<'a> = Result<Option<TokenResult<'a>>, LexError>;
+
+/// A token matcher method, as run by `Lexer::test`
+type Rule<'a> = fn(&mut Lexer<'a>) -> RuleResult<'a>;
+
 /**
  * The Lexer struct
  *
@@ -100,30 +336,39 @@ pub struct Lexer<'a> {
     input: &'a str,
     filename: Option<String>,
     position: usize,
-    deferred_tokens: Vec<Token>,
+    deferred_tokens: VecDeque<Token<'a>>,
     last_indents: u32,
     line_number: u32,
-    stash: Vec<Token>,
-    indent_stack: Vec<Token>,
-    indent_regex: (),
-    pipeless: bool
+    stash: VecDeque<Token<'a>>,
+    indent_stack: Vec<u32>,
+    indent_char: Option<char>,
+    pipeless: bool,
+    pipeless_indents: Option<u32>,
+    finished: bool,
+    location: Position
 }
 
 impl<'a> Lexer<'a> {
     /// Allows for filename to be specified - use new or new_with_filename
+    ///
+    /// A byte order mark at the start of the input is skipped
     #[inline]
     fn new_with_option(input: &'a str, filename: Option<String>) -> Lexer {
+        let bom = if input.starts_with('\u{feff}') { '\u{feff}'.len_utf8() } else { 0 };
         Lexer {
             input: input,
             filename: filename,
-            position: 0,
-            deferred_tokens: Vec::new(),
+            position: bom,
+            deferred_tokens: VecDeque::new(),
             last_indents: 0,
             line_number: 1,
-            stash: Vec::new(),
+            stash: VecDeque::new(),
             indent_stack: Vec::new(),
-            indent_regex: (),
-            pipeless: false
+            indent_char: None,
+            pipeless: false,
+            pipeless_indents: None,
+            finished: false,
+            location: Position { offset: bom, ..Position::start() }
         }
     }
 
@@ -141,37 +386,57 @@ impl<'a> Lexer<'a> {
 
     /// Get remaining input as slice
     #[inline]
-    fn get_input(&self) -> &str {
+    fn get_input(&self) -> &'a str {
         &self.input[self.position..]
     }
 
     /// Create a new token, with line number
     /// The sole purpose of this function is to add line number
     #[inline]
-    fn tok(&self, token_type: TokenType) -> Token {
+    fn tok(&self, token_type: TokenType<'a>) -> Token<'a> {
         Token::new(token_type, self.line_number)
     }
 
+    /// Create a new token with the span of `len` bytes, `skip` bytes into
+    /// the remaining input, for a rule giving tokens for parts of its input
+    fn tok_at(&self, token_type: TokenType<'a>, skip: usize, len: usize) -> Token<'a> {
+        let mut tok = self.tok(token_type);
+        tok.span = self.span_at(skip, len);
+        tok
+    }
+
+    /// The span of `len` bytes, `skip` bytes into the remaining input
+    fn span_at(&self, skip: usize, len: usize) -> Span {
+        let input = self.get_input();
+        let start = self.location.after(&input[..skip]);
+        Span { start, end: start.after(&input[skip..skip + len]) }
+    }
+
     /// Consume amt number of bytes of the input, returning
     /// it as a slice
     #[inline]
-    pub fn consume(&mut self, amt: usize) -> &str {
+    pub fn consume(&mut self, amt: usize) -> &'a str {
         self.position += amt;
-        &self.input[self.position - amt .. self.position] // as if the consume didn't happen
+        let consumed = &self.input[self.position - amt .. self.position];
+        self.location = self.location.after(consumed);
+        consumed // as if the consume didn't happen
     }
 
 
     /// Return the next char
     #[inline]
     pub fn peek(&self) -> Option<char> {
-        self.input.chars().nth(self.position)
+        self.get_input().chars().next()
     }
 
     /**
      * Scan for a regex and create a simple token on match
      * TODO I think I can remove this
+     *
+     * Not called `scan`, as `Iterator::scan` would take precedence in
+     * method call syntax
      */
-    pub fn scan(&mut self, re: regex::Regex) -> Option<String> {
+    pub fn scan_regex(&mut self, re: regex::Regex) -> Option<&'a str> {
         let (res, consume_len) = match re.captures(&self.input[self.position..]) {
             // Fail if match failed
             Some(captures) => {
@@ -183,7 +448,7 @@ impl<'a> Lexer<'a> {
                     Some((x, y)) if x == 0 => {
                         match captures.at(1) {
                             Some(cap) => (
-                                Some(cap.to_string()),
+                                Some(cap),
                                 // We have already tested for existence,
                                 // so safe to unwrap
                                 captures.at(0).unwrap().len()
@@ -200,268 +465,3059 @@ impl<'a> Lexer<'a> {
         res
     }
 
-    /// Push token onto stack for later use
+    /// Push token onto queue for later use
     #[inline]
-    pub fn defer(&mut self, tok: Token) {
-        self.deferred_tokens.push(tok)
+    pub fn defer(&mut self, tok: Token<'a>) {
+        self.deferred_tokens.push_back(tok)
     }
 
-    /// Return amt tokens
-    pub fn lookahead(&mut self, amt: usize) -> &Token {
-        let len = amt - self.stash.len();
-        for _ in 1..len {
-            let next = self.next();
-            self.stash.push(next);
+    /// Return the token amt tokens ahead (1 is the next token), without
+    /// consuming it
+    ///
+    /// # Panics
+    ///
+    /// If `amt` is 0
+    pub fn lookahead(&mut self, amt: usize) -> Result<&Token<'a>, LexError> {
+        assert!(amt >= 1, "lookahead starts at 1, the next token");
+        while self.stash.len() < amt {
+            let next = self.advance()?;
+            self.stash.push_back(next);
         }
-        &self.stash[amt-1]
+        Ok(&self.stash[amt-1])
     }
 
-    /// Get the contents of a bracketed expression
-    pub fn bracket_expression(&self, skip: u32) {
-        
+    /// Get the contents of a bracketed expression, starting with the
+    /// bracket `skip` bytes into the remaining input
+    pub fn bracket_expression(&self, skip: usize) -> Result<BracketBlock<'a>, LexError> {
+        let input = &self.get_input()[skip..];
+        let end = match input.chars().next() {
+            Some('(') => ')',
+            Some('{') => '}',
+            Some('[') => ']',
+            _ => return Err(self.error_at(
+                LexErrorKind::UnexpectedCharacter,
+                skip,
+                input.chars().next().map_or(0, |ch| ch.len_utf8()),
+                "unrecognised start of bracketed expression"
+            ))
+        };
+        match brackets::parse_max(&input[1..]) {
+            Some(block) => {
+                if !input[1 + block.src.len()..].starts_with(end) {
+                    return Err(self.error_at(
+                        LexErrorKind::UnterminatedBracket,
+                        skip,
+                        1 + block.src.len(),
+                        &format!("expected {:?} to close bracketed expression", end)
+                    ));
+                }
+                Ok(block)
+            },
+            None => Err(self.error_at(
+                LexErrorKind::UnterminatedBracket,
+                skip,
+                1,
+                &format!("unterminated bracket, expected {:?}", end)
+            ))
+        }
     }
 
-    /// Pop off the token stash
+    /// The length of the expression `skip` bytes into the remaining
+    /// input, see `expression_len`. A bracket or string left open is an
+    /// error from where it was opened to the end of that line
+    fn checked_expression_len(&self, skip: usize) -> Result<usize, LexError> {
+        let input = &self.get_input()[skip..];
+        expression_len(input).map_err(|idx| {
+            let (what, close) = match input[idx..].chars().next().unwrap() {
+                '(' => ("bracket", ')'),
+                '{' => ("bracket", '}'),
+                '[' => ("bracket", ']'),
+                quote => ("string", quote)
+            };
+            self.error_at(
+                LexErrorKind::UnterminatedBracket,
+                skip + idx,
+                line_len(&input[idx..]),
+                &format!("unterminated {}, expected {:?}", what, close)
+            )
+        })
+    }
+
+    /// Take the oldest token off the token stash
     #[inline]
-    pub fn stashed(&mut self) -> Option<Token> {
-        self.stash.pop()
+    pub fn stashed(&mut self) -> Option<Token<'a>> {
+        self.stash.pop_front()
     }
 
-    /// Pop off the deferred token stack
+    /// Take the oldest token off the deferred token queue
     #[inline]
-    pub fn deferred(&mut self) -> Option<Token> {
-        self.deferred_tokens.pop()
+    pub fn deferred(&mut self) -> Option<Token<'a>> {
+        self.deferred_tokens.pop_front()
     }
 
     /// Get the next token
-    pub fn next(&mut self) -> Token {
-        self.tok(TokenType::Outdent) // TODO placeholder
+    ///
+    /// After an error the input isn't consumed, so the same error is
+    /// returned again
+    pub fn next(&mut self) -> Result<Token<'a>, LexError> {
+        match self.stashed() {
+            Some(tok) => Ok(tok),
+            None => self.advance()
+        }
+    }
+
+    /// Lex the whole input, carrying on after errors
+    ///
+    /// After an error the rest of its line is skipped (the whole line, for
+    /// an error in its indentation), along with any following lines whose
+    /// indentation is also invalid. Gives the tokens found, up to and
+    /// including `EndOfSource`, and every error.
+    pub fn lex_recovering(mut self) -> (Vec<Token<'a>>, Vec<LexError>) {
+        let mut tokens = Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            match Lexer::next(&mut self) {
+                Ok(tok) => {
+                    let end = tok.token_type == TokenType::EndOfSource;
+                    tokens.push(tok);
+                    if end {
+                        return (tokens, errors);
+                    }
+                },
+                Err(err) => {
+                    errors.push(err);
+                    self.skip_invalid_lines();
+                }
+            }
+        }
+    }
+
+    /// Skip to the next line with valid indentation, after an error
+    fn skip_invalid_lines(&mut self) {
+        let input = self.get_input();
+        let mut len = if input.starts_with('\n') { 1 } else { 0 };
+        loop {
+            len += input[len..].find('\n').unwrap_or(input.len() - len);
+            let line = match input[len..].split('\n').nth(1) {
+                Some(line) => line,
+                None => break
+            };
+            let indents = line.len() - line.trim_start_matches([' ', '\t']).len();
+            if line.trim().is_empty() || self.indentation_error(&line[..indents]).is_none() {
+                break;
+            }
+            len += 1;
+        }
+        self.line_number += input[..len].matches('\n').count() as u32;
+        self.consume(len);
+    }
+
+    /// Lex the next token from the input, ignoring the stash
+    ///
+    /// The rules are tried in order, and the first to match wins, so
+    /// the order here is significant (it follows jade.js).
+    fn advance(&mut self) -> Result<Token<'a>, LexError> {
+        let rules: &[Rule<'a>] = &[
+            Lexer::blank,
+            Lexer::eos,
+            Lexer::pipeless_text,
+            Lexer::doctype,
+            Lexer::interpolation,
+            Lexer::case,
+            Lexer::when,
+            Lexer::default,
+            Lexer::extends,
+            Lexer::append,
+            Lexer::prepend,
+            Lexer::mixin_block,
+            Lexer::block,
+            Lexer::include,
+            Lexer::mixin,
+            Lexer::call,
+            Lexer::conditional,
+            Lexer::each,
+            Lexer::while_loop,
+            Lexer::tag,
+            Lexer::text_filter,
+            Lexer::block_code,
+            Lexer::code,
+            Lexer::id,
+            Lexer::dot,
+            Lexer::class_name,
+            Lexer::attrs,
+            Lexer::attributes_block,
+            Lexer::indent,
+            Lexer::text,
+            Lexer::text_html,
+            Lexer::comment,
+            Lexer::slash,
+            Lexer::colon,
+            Lexer::fail
+        ];
+        'restart: loop {
+            if let Some(tok) = self.deferred() {
+                return Ok(tok);
+            }
+            for &rule in rules {
+                match self.test(rule)? {
+                    Some(Token { token_type: TokenType::Nothing, .. }) => {
+                        continue 'restart;
+                    },
+                    Some(tok) => {
+                        return Ok(tok);
+                    },
+                    None => {}
+                }
+            }
+        }
     }
 
     /// Test the input against a rule
-    fn test(&mut self, f: fn(&str) -> Option<TokenResult>) -> Option<Token> {
-        match f(self.get_input()) {
-            Some(res) => {
+    ///
+    /// The token, and any tokens the rule deferred, are given the span of
+    /// the input the rule consumed, unless the rule gave them their own
+    fn test(&mut self, f: Rule<'a>) -> Result<Option<Token<'a>>, LexError> {
+        let deferred = self.deferred_tokens.len();
+        match f(self) {
+            Ok(Some(mut res)) => {
+                let start = self.location;
                 self.consume(res.input_increment);
                 self.line_number = self.line_number + res.line_increment;
-                Some(res.token)
+                let span = Span { start, end: self.location };
+                let toks = Some(&mut res.token).into_iter().chain(self.deferred_tokens.iter_mut().skip(deferred));
+                for tok in toks.filter(|tok| tok.span == Span::default()) {
+                    tok.span = span;
+                }
+                Ok(Some(res.token))
             },
-            _ => None
+            Ok(None) => Ok(None),
+            Err(err) => Err(err)
         }
     }
 
     // Tokens
     // ======
 
-    /// End of source. Need mut ref to pop indent_stack
-    fn eos(&mut self) -> Option<TokenResult> {
+    /// End of source, after an `Outdent` for each remaining indent.
+    /// Need mut ref to pop indent_stack
+    fn eos(&mut self) -> RuleResult<'a> {
         if self.position != self.input.len() {
-            None
+            Ok(None)
         } else {
-            if self.indent_stack.len() > 0 {
-                self.indent_stack.pop();
-                Some(TokenResult::new(self.tok(TokenType::Outdent), 0, 0))
+            if self.indent_stack.pop().is_some() {
+                Ok(Some(TokenResult::new(self.tok(TokenType::Outdent), 0, 0)))
             } else {
-                Some(TokenResult::new(self.tok(TokenType::EndOfSource), 0, 0))
+                Ok(Some(TokenResult::new(self.tok(TokenType::EndOfSource), 0, 0)))
             }
         }
     }
 
+    /// Switch to pipeless mode, where the following indented block is text
+    fn start_pipeless(&mut self) {
+        self.pipeless = true;
+        self.pipeless_indents = None;
+    }
+
+    /// Switch out of pipeless mode
+    fn end_pipeless(&mut self) {
+        self.pipeless = false;
+        self.pipeless_indents = None;
+    }
+
     /// Blank line
-    fn blank(&self) -> Option<TokenResult> {
-        match regex!(r"^\n *\n").find(self.get_input()) {
+    fn blank(&mut self) -> RuleResult<'a> {
+        match regex!(r"^\r?\n[ \t]*\r?\n").find(self.get_input()) {
             Some((0, end)) => {
-                let res = &self.get_input()[..end];
                 if self.pipeless {
-                    Some(TokenResult::new(self.tok(TokenType::Text("".to_string())), end-1, 1))
+                    // only text if the block carries on after the blank lines
+                    let next_line = self.get_input()[end..].split('\n')
+                        .find(|line| !line.trim().is_empty());
+                    let indents = next_line.map(|line| {
+                        line.len() - line.trim_start_matches([' ', '\t']).len()
+                    });
+                    if indents.is_none_or(|indents| indents as u32 <= self.last_indents) {
+                        self.end_pipeless();
+                    }
+                }
+                if self.pipeless {
+                    Ok(Some(TokenResult::new(self.tok(TokenType::Text(Cow::Borrowed(""))), end-1, 1)))
                 } else {
-                    Some(TokenResult::new(self.tok(TokenType::Nothing), end-1, 1))
+                    Ok(Some(TokenResult::new(self.tok(TokenType::Nothing), end-1, 1)))
                 }
             },
-            _ => None
+            _ => Ok(None)
         }
     }
 
     /// Comment ('//-' is not output in html)
-    fn comment(&mut self) -> Option<TokenResult> {
+    fn comment(&mut self) -> RuleResult<'a> {
         let mut pipeless = self.pipeless;
-        let res = match regex!(r"^//(-)?([^\n]*)").captures(self.get_input()) {
-            Some(capture) => {
-                pipeless = true;
-                let comment = match capture.at(2) {
-                    Some(msg) => Some(msg.to_string()),
-                    None => None
-                };
-                Some(TokenResult::new(
-                    self.tok(TokenType::Comment(comment, capture.at(1) == None)),
-                    capture.at(0).unwrap().len(), // must be Some<>
-                    0
-                ))
-
-            }
-            None => None
+        let input = self.get_input();
+        let res = if let Some(body) = input.strip_prefix("//") {
+            pipeless = true;
+            self.pipeless_indents = None;
+            let buffer = !body.starts_with('-');
+            let start = if buffer { 2 } else { 3 };
+            let len = line_len(input);
+            Ok(Some(TokenResult::new(
+                self.tok(TokenType::Comment(Some(&input[start..len]), buffer)),
+                len,
+                0
+            )))
+        } else {
+            Ok(None)
         };
         self.pipeless = pipeless;
         res
     }
 
-    // TODO what is this?
-    // TODO doing bracket matching is hard. Jade.js uses a lib, should I?
-    /*
-    fn interpolation(&self) -> Option<TokenResult> {
-        match regex!(r"^#\{").is_match(self.get_input()) {
-            true => {
-                // TODO I've just stopped mid line :P
+    /// Tag, e.g. `html`, `svg:rect` or `my-element`
+    fn tag(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        match regex!(r"^\w(?:[-:\w]*\w)?").find(input) {
+            Some((0, end)) => {
+                let name = &input[..end];
+                Ok(Some(TokenResult::new(
+                    self.tok(TokenType::Tag(name)),
+                    name.len(),
+                    0
+                )))
             },
-            false => None
+            _ => Ok(None)
         }
-    }*/
+    }
 
-}
+    /// Switch, e.g. `case friends.length`
+    fn case(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        let start = match regex!(r"^case\b[ \t]*").find(input) {
+            Some((0, end)) => end,
+            _ => return Ok(None)
+        };
+        let len = self.checked_expression_len(start)?;
+        let expr = input[start..start + len].trim();
+        if expr.is_empty() {
+            return Err(self.error(LexErrorKind::Malformed, "expected an expression after \"case\""));
+        }
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::Case(expr)),
+            start + len,
+            expr.matches('\n').count() as u32
+        )))
+    }
 
-impl <'a> fmt::Debug for Lexer<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Lexer {{ input: {}, position: {} }}", self.input, self.position)
+    /// Branch of a case, e.g. `when 0` or `when 'a': p A`
+    ///
+    /// The expression stops at a `:` outside of any nesting, which is
+    /// left for block expansion
+    fn when(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        let start = match regex!(r"^when\b[ \t]*").find(input) {
+            Some((0, end)) => end,
+            _ => return Ok(None)
+        };
+        let mut len = self.checked_expression_len(start)?;
+        let mut state: BracketState = Default::default();
+        for (idx, ch) in input[start..start + len].char_indices() {
+            if ch == ':' && !state.in_nesting() {
+                len = idx;
+                break;
+            }
+            brackets::parse_char_from_state(ch, &mut state);
+        }
+        let expr = input[start..start + len].trim();
+        if expr.is_empty() {
+            return Err(self.error(LexErrorKind::Malformed, "expected an expression after \"when\""));
+        }
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::When(expr)),
+            start + len,
+            input[start..start + len].matches('\n').count() as u32
+        )))
     }
-}
 
-// ======================
-//
-// Tests
-//
-// ======================
+    /// Default branch of a case
+    fn default(&mut self) -> RuleResult<'a> {
+        match regex!(r"^default\b[ \t]*").find(self.get_input()) {
+            Some((0, end)) => Ok(Some(TokenResult::new(self.tok(TokenType::Default), end, 0))),
+            _ => Ok(None)
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use lexer::{Token, TokenType, TokenResult, Lexer};
-    use regex;
+    /// Template inheritance, e.g. `extends layout.jade`
+    fn extends(&mut self) -> RuleResult<'a> {
+        if !self.get_input().starts_with("extend") {
+            return Ok(None);
+        }
+        match regex!(r"^extends?\b[ \t]*([^\n]*)").captures(self.get_input()) {
+            Some(capture) => {
+                let path = capture.at(1).unwrap().trim();
+                if path.is_empty() {
+                    return Err(self.error(LexErrorKind::Malformed, "missing path for extends"));
+                }
+                Ok(Some(TokenResult::new(
+                    self.tok(TokenType::Extends(path)),
+                    capture.at(0).unwrap().len(),
+                    0
+                )))
+            },
+            None => Ok(None)
+        }
+    }
 
-    fn jade_block<'a>() -> &'a str {
-        concat!(
-            "doctype html",
-            "html(lang=\"en\")",
-            "  head",
-            "    title= pageTitle",
-            "    script(type='text/javascript').",
-            "      if (foo) {",
-            "         bar(1 + 5)",
-            "      }",
-            "  body",
-            "    h1 Jade - node template engine",
-            "    #container.col",
-            "      if youAreUsingJade",
-            "        p You are amazing",
-            "      else",
-            "        p Get on it!",
-            "      p.",
-            "        Jade is a terse and simple",
-            "        templating language with a",
-            "        strong focus on performance",
-            "        and powerful features.")
+    /// Append to a block, e.g. `append scripts`
+    fn append(&mut self) -> RuleResult<'a> {
+        if !self.get_input().starts_with("append") {
+            return Ok(None);
+        }
+        match regex!(r"^append\b[ \t]*([^\n]*)").captures(self.get_input()) {
+            Some(capture) => self.block_result(
+                capture.at(1).unwrap(),
+                BlockType::Append,
+                capture.at(0).unwrap().len()
+            ),
+            None => Ok(None)
+        }
     }
 
-    #[test]
-    fn new() {
-        let ls = Lexer::new(jade_block());
-        assert_eq!(ls, Lexer {
-                input: jade_block(),
-                filename: None,
-                position: 0,
-                deferred_tokens: Vec::new(),
-                last_indents: 0,
-                line_number: 1,
-                stash: Vec::new(),
-                indent_stack: Vec::new(),
-                indent_regex: (),
-                pipeless: false
-        })
+    /// Prepend to a block, e.g. `prepend head`
+    fn prepend(&mut self) -> RuleResult<'a> {
+        if !self.get_input().starts_with("prepend") {
+            return Ok(None);
+        }
+        match regex!(r"^prepend\b[ \t]*([^\n]*)").captures(self.get_input()) {
+            Some(capture) => self.block_result(
+                capture.at(1).unwrap(),
+                BlockType::Prepend,
+                capture.at(0).unwrap().len()
+            ),
+            None => Ok(None)
+        }
     }
 
-    #[test]
-    fn consume() {
-        let test_str = "function testfn() { }";
-        let mut ls = Lexer::new(test_str);
-        assert_eq!(ls.consume(10), "function t");
-        assert_eq!(ls.consume(5), "estfn");
-        assert_eq!(ls.position, 15);
+    /// Block, e.g. `block content`, `block append scripts` or
+    /// `block prepend head`
+    fn block(&mut self) -> RuleResult<'a> {
+        if !self.get_input().starts_with("block") {
+            return Ok(None);
+        }
+        let re = regex!(r"^block\b[ \t]*(?:(append|prepend)[ \t]+)?([^\n]*)");
+        match re.captures(self.get_input()) {
+            Some(capture) => {
+                let block_type = match capture.at(1) {
+                    Some("append") => BlockType::Append,
+                    Some(_) => BlockType::Prepend,
+                    None => BlockType::Replace
+                };
+                self.block_result(capture.at(2).unwrap(), block_type, capture.at(0).unwrap().len())
+            },
+            None => Ok(None)
+        }
     }
 
-    #[test]
-    #[ignore] // need tokens working to test this
-    fn lookahead() {
-        let test_str = "function testfn() { }";
-        let ls = Lexer::new(test_str);
+    /// Create the result for a block token
+    fn block_result(&self, name: &'a str, block_type: BlockType, len: usize) -> RuleResult<'a> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(self.error(LexErrorKind::Malformed, "missing block name"));
+        }
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::Block { name, block_type }),
+            len,
+            0
+        )))
     }
 
-    #[test]
-    #[ignore] // need tokens working to test this
-    fn peek() {
-        let test_str = "function testfn() { }";
-        let ls = Lexer::new(test_str);
+    /// Mixin block, `block` on a line of its own
+    fn mixin_block(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        match regex!(r"^block[ \t]*").find(input) {
+            Some((0, end)) if at_line_end(&input[end..]) => {
+                Ok(Some(TokenResult::new(self.tok(TokenType::MixinBlock), end, 0)))
+            },
+            _ => Ok(None)
+        }
     }
 
-    // TODO use token-like strings to test
-    #[test]
-    fn scan() {
-        let test_str = "function testfn() { }";
-        let mut ls = Lexer::new(test_str);
-        // first regex should match "function" and capture "unc"
-        let re = regex::Regex::new(r"[fg](unc)tion").unwrap();
-        assert_eq!(ls.scan(re), Some("unc".to_string()));
-        // second regex should fail
-        let re2 = regex::Regex::new(r" ?(\(\)) ").unwrap();
-        assert_eq!(ls.scan(re2), None);
-        // third regex should match " testfn" and capture "testfn"
-        let re3 = regex::Regex::new(r" ?(t?e?s?t?t?f?n+)").unwrap();
-        assert_eq!(ls.scan(re3), Some("testfn".to_string()));
+    /// Include, e.g. `include header.jade` or `include:coffee(opts) file.coffee`
+    fn include(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        let mut len = match regex!(r"^include\b").find(input) {
+            Some((0, end)) => end,
+            _ => return Ok(None)
+        };
+        let (filters, filters_len) = self.filter_chain(len)?;
+        len += filters_len;
+        let path = match regex!(r"^[ \t]+([^\n]*)").captures(&input[len..]) {
+            Some(capture) => {
+                len += capture.at(0).unwrap().len();
+                capture.at(1).unwrap().trim()
+            },
+            None => ""
+        };
+        if path.is_empty() {
+            return Err(self.error(LexErrorKind::Malformed, "missing path for include"));
+        }
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::Include {
+                path,
+                raw: filters.is_empty() && extension(path).is_some_and(|ext| ext != "jade"),
+                filters
+            }),
+            len,
+            input[..len].matches('\n').count() as u32
+        )))
     }
 
-    #[test]
-    fn eos() {
-        let mut true1 = Lexer::new("");
-        let mut false1 = Lexer::new("notend");
-        assert_eq!(true1.eos(), Some(TokenResult::new(
-            true1.tok(TokenType::EndOfSource), 0, 0
-        )));
-        assert_eq!(false1.eos(), None);
+    /// A chain of filters with their options, `skip` bytes into the
+    /// remaining input, e.g. `:coffee(bare=true):uglify-js`, along with
+    /// its length
+    fn filter_chain(&self, skip: usize) -> Result<(Vec<Filter<'a>>, usize), LexError> {
+        let input = self.get_input();
+        let mut filters = Vec::new();
+        let mut len = skip;
+        let re = regex!(r"^:[\w-]+");
+        while let Some((0, end)) = re.find(&input[len..]) {
+            let name = &input[len + 1..len + end];
+            len += end;
+            let mut options = Vec::new();
+            if input[len..].starts_with('(') {
+                let block = self.bracket_expression(len)?;
+                options = self.attributes(len, &block)?;
+                len += block.src.len() + 2;
+            }
+            filters.push(Filter::new(name, options));
+        }
+        Ok((filters, len - skip))
     }
 
-    #[test]
-    fn blank() {
-        let true1 = Lexer::new("\n        \n");
-        let true2 = Lexer::new("\n\n");
-        let false1 = Lexer::new("\nSome text this line\n");
-        assert_eq!(true1.blank(), Some(TokenResult::new(
-            true1.tok(TokenType::Nothing), 9, 1
-        )));
-        assert_eq!(true2.blank(), Some(TokenResult::new(
-            true2.tok(TokenType::Nothing), 1, 1
-        )));
-        assert_eq!(false1.blank(), None);
+    /// Filter, e.g. `:markdown` or `:uglify-js:coffee(bare=true)`,
+    /// followed by a pipeless text block. A chain gives a token for
+    /// each filter, outermost first
+    fn text_filter(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        if !regex!(r"^:[\w-]").is_match(input) {
+            return Ok(None);
+        }
+        let (filters, mut len) = self.filter_chain(0)?;
+        if let Some((0, end)) = regex!(r"^[ \t]*").find(&input[len..]) {
+            len += end;
+        }
+        let mut filters = filters.into_iter();
+        let first = filters.next().unwrap();
+        for filter in filters {
+            let tok = self.tok(TokenType::Filter { name: filter.name, options: filter.options });
+            self.defer(tok);
+        }
+        self.start_pipeless();
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::Filter { name: first.name, options: first.options }),
+            len,
+            input[..len].matches('\n').count() as u32
+        )))
     }
 
-    #[test]
-    fn comment() {
-        let mut true1 = Lexer::new("// This is a comment\nThis is another line");
-        let mut true2 = Lexer::new("//- This is an unbuffered comment");
-        let mut false1 = Lexer::new("This is not a comment // this is not the next token");
-        assert_eq!(true1.comment(), Some(TokenResult::new(
-            true1.tok(TokenType::Comment(Some(" This is a comment".to_string()), true)),
-            "// This is a comment".len(),
-            0
-        )));
-        assert_eq!(true2.comment(), Some(TokenResult::new(
-            true1.tok(TokenType::Comment(
-                Some(" This is an unbuffered comment".to_string()),
-                false
-            )),
-            "//- This is an unbuffered comment".len(),
-            0
-        )));
-        assert_eq!(false1.comment(), None);
+    /// Mixin declaration, e.g. `mixin list(id, ...items)`
+    fn mixin(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        if !input.starts_with("mixin") {
+            return Ok(None);
+        }
+        let (name, mut len) = match regex!(r"^mixin[ \t]+([-\w]+)[ \t]*").captures(input) {
+            Some(capture) => (capture.at(1).unwrap(), capture.at(0).unwrap().len()),
+            None => return Ok(None)
+        };
+        let mut params = Vec::new();
+        if input[len..].starts_with('(') {
+            let block = self.bracket_expression(len)?;
+            params = split_args(block.src);
+            len += block.src.len() + 2;
+        }
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::Mixin { name, params }),
+            len,
+            input[..len].matches('\n').count() as u32
+        )))
+    }
+
+    /// Mixin call, e.g. `+list('x', 1, 2)` or `+#{name}()`
+    ///
+    /// Brackets after the name are only arguments if they don't look
+    /// like attributes, which are left for `attrs`
+    fn call(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        if !input.starts_with('+') {
+            return Ok(None);
+        }
+        let (name, mut len) = match regex!(r"^\+[ \t]*(?:([-\w]+)|#\{)").captures(input) {
+            Some(capture) => match capture.at(1) {
+                Some(name) => (name, capture.at(0).unwrap().len()),
+                None => {
+                    let skip = capture.at(0).unwrap().len() - 1;
+                    let block = self.bracket_expression(skip)?;
+                    let len = skip + block.src.len() + 2;
+                    (&input[skip - 1..len], len)
+                }
+            },
+            None => return Ok(None)
+        };
+        let mut args = Vec::new();
+        if let Some((0, end)) = regex!(r"^[ \t]*\(").find(&input[len..]) {
+            let block = self.bracket_expression(len + end - 1)?;
+            if !regex!(r"^\s*[-\w]+[ \t]*!?=").is_match(block.src) {
+                args = split_args(block.src);
+                len += end + block.src.len() + 1;
+            }
+        }
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::Call { name, args }),
+            len,
+            input[..len].matches('\n').count() as u32
+        )))
+    }
+
+    /// Conditional, e.g. `if expr`, `else`, or in code, `- if (expr)`
+    fn conditional(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        if !starts_with_any(input, b"-iue") {
+            return Ok(None);
+        }
+        let start = match regex!(r"^(?:-[ \t]*)?(?:if|unless|else[ \t]+if|else)\b[ \t]*").find(input) {
+            Some((0, end)) => end,
+            _ => return Ok(None)
+        };
+        let code = input.starts_with('-');
+        let keyword = input[..start].trim_start_matches('-').trim();
+        let len = self.checked_expression_len(start)?;
+        let mut expr = input[start..start + len].trim();
+        if code && expr.starts_with('(') {
+            // `- if (expr)` is javascript, so drop the brackets
+            if let Some(block) = brackets::parse_max(&expr[1..]) {
+                if block.src.len() + 2 == expr.len() {
+                    expr = block.src.trim();
+                }
+            }
+        }
+        let token_type = match keyword {
+            "else" if !expr.is_empty() && !expr.starts_with(':') => {
+                return Err(self.error(LexErrorKind::Malformed, &format!("unexpected condition {:?} after else", expr)));
+            },
+            "else" => {
+                return Ok(Some(TokenResult::new(self.tok(TokenType::Else), start, 0)));
+            },
+            _ if expr.is_empty() => {
+                return Err(self.error(LexErrorKind::Malformed, &format!("expected a condition after {:?}", keyword)));
+            },
+            "if" => TokenType::If(expr),
+            "unless" => TokenType::Unless(expr),
+            _ => TokenType::ElseIf(expr)
+        };
+        Ok(Some(TokenResult::new(
+            self.tok(token_type),
+            start + len,
+            input[start..start + len].matches('\n').count() as u32
+        )))
+    }
+
+    /// Iteration, e.g. `each item, idx in items` or `for val, key in obj`
+    fn each(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        if !starts_with_any(input, b"-ef") {
+            return Ok(None);
+        }
+        if !regex!(r"^(?:-[ \t]*)?(?:each|for)\b").is_match(input) {
+            return Ok(None);
+        }
+        let re = regex!(r"^(?:-[ \t]*)?(?:each|for)[ \t]+([a-zA-Z_$][\w$]*)(?:[ \t]*,[ \t]*([a-zA-Z_$][\w$]*))?[ \t]+in\b[ \t]*");
+        let (value, key, start) = match re.captures(input) {
+            Some(capture) => (
+                capture.at(1).unwrap(),
+                capture.at(2),
+                capture.at(0).unwrap().len()
+            ),
+            None => {
+                // `- for (...)` is just javascript
+                if regex!(r"^(?:each|for)\b").is_match(input) {
+                    let line = input.split('\n').next().unwrap();
+                    return Err(self.error(LexErrorKind::Malformed, &format!("malformed each {:?}", line.trim())));
+                }
+                return Ok(None);
+            }
+        };
+        let len = self.checked_expression_len(start)?;
+        let expr = input[start..start + len].trim();
+        if expr.is_empty() {
+            return Err(self.error(LexErrorKind::Malformed, "expected an expression to iterate over"));
+        }
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::Each {
+                value,
+                key,
+                expr
+            }),
+            start + len,
+            expr.matches('\n').count() as u32
+        )))
+    }
+
+    /// Loop, e.g. `while n < 4`
+    fn while_loop(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        let start = match regex!(r"^while\b[ \t]*").find(input) {
+            Some((0, end)) => end,
+            _ => return Ok(None)
+        };
+        let len = self.checked_expression_len(start)?;
+        let expr = input[start..start + len].trim();
+        if expr.is_empty() {
+            return Err(self.error(LexErrorKind::Malformed, "expected a condition after \"while\""));
+        }
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::While(expr)),
+            start + len,
+            expr.matches('\n').count() as u32
+        )))
+    }
+
+    /// Unbuffered code block, `-` followed by an indented body
+    fn block_code(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        let start = match regex!(r"^-[ \t]*").find(input) {
+            Some((0, end)) if at_line_end(&input[end..]) => end,
+            _ => return Ok(None)
+        };
+        let mut lines = Vec::new();
+        let mut len = start;
+        // the `-` line's break may be `\r\n`
+        let mut line_end = start + input[start..].find('\n').unwrap_or(0);
+        let mut block_indents = None;
+        for line in input[start..].split('\n').skip(1) {
+            line_end += 1 + line.len();
+            let line = &line[..line_len(line)];
+            let indents = line.len() - line.trim_start_matches([' ', '\t']).len();
+            if !line.trim().is_empty() {
+                if indents as u32 <= self.last_indents {
+                    break;
+                }
+                let block_indents = *block_indents.get_or_insert(indents);
+                lines.push(&line[cmp::min(indents, block_indents)..]);
+                len = line_end;
+            } else {
+                lines.push("");
+            }
+        }
+        // trailing blank lines aren't part of the block
+        let line_count = input[..len].matches('\n').count();
+        lines.truncate(line_count);
+        if lines.is_empty() {
+            return Err(self.error(LexErrorKind::Malformed, "expected an expression after \"-\""));
+        }
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::Code {
+                buffered: false,
+                escaped: false,
+                expr: Cow::Owned(lines.join("\n"))
+            }),
+            len,
+            line_count as u32
+        )))
+    }
+
+    /// Code on a single line, e.g. `= name` or `- var x = 1`, carrying on
+    /// over line breaks inside brackets
+    fn code(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        if !starts_with_any(input, b"!=-") {
+            return Ok(None);
+        }
+        let (flags, start) = match regex!(r"^(?:!?=|-)[ \t]*").find(input) {
+            Some((0, end)) => (input[..end].trim_end_matches([' ', '\t']), end),
+            _ => return Ok(None)
+        };
+        let len = self.checked_expression_len(start)?;
+        let expr = input[start..start + len].trim();
+        if expr.is_empty() {
+            return Err(self.error(LexErrorKind::Malformed, &format!("expected an expression after {:?}", flags)));
+        }
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::Code {
+                buffered: flags != "-",
+                escaped: flags == "=",
+                expr: Cow::Borrowed(expr)
+            }),
+            start + len,
+            expr.matches('\n').count() as u32
+        )))
+    }
+
+    /// Id shorthand, e.g. `#container`
+    fn id(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        match regex!(r"^#[\w-]+").find(input) {
+            Some((0, end)) => Ok(Some(TokenResult::new(self.tok(TokenType::Id(&input[1..end])), end, 0))),
+            _ => Ok(None)
+        }
+    }
+
+    /// Class shorthand, e.g. `.col`, which like a tag or id may use any
+    /// unicode word characters
+    fn class_name(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        match regex!(r"^\.-?-?(?:_|[^\W\d])[\w-]*").find(input) {
+            Some((0, end)) => {
+                Ok(Some(TokenResult::new(self.tok(TokenType::Class(&input[1..end])), end, 0)))
+            },
+            _ => match regex!(r"^\.[\w-]+").find(input) {
+                Some((0, end)) => Err(self.error_at(
+                    LexErrorKind::InvalidAttribute,
+                    0,
+                    end,
+                    &format!("invalid class name {:?}", &input[..end])
+                )),
+                _ => Ok(None)
+            }
+        }
+    }
+
+    /// Attributes, e.g. `(lang="en", class=foo ? 'a' : 'b', checked)`
+    fn attrs(&mut self) -> RuleResult<'a> {
+        if !self.get_input().starts_with('(') {
+            return Ok(None);
+        }
+        let block = self.bracket_expression(0)?;
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::Attrs(self.attributes(0, &block)?)),
+            block.src.len() + 2,
+            block.src.matches('\n').count() as u32
+        )))
+    }
+
+    /// Parse the attributes in a bracketed expression `skip` bytes into
+    /// the remaining input
+    fn attributes(&self, skip: usize, block: &BracketBlock<'a>) -> Result<Vec<Attribute<'a>>, LexError> {
+        parse_attributes(block.src).map_err(|msg| {
+            self.error_at(LexErrorKind::InvalidAttribute, skip, block.src.len() + 2, &msg)
+        })
+    }
+
+    /// Attribute spread, e.g. `&attributes(obj)`
+    fn attributes_block(&mut self) -> RuleResult<'a> {
+        if !regex!(r"^&attributes\b").is_match(self.get_input()) {
+            return Ok(None);
+        }
+        let skip = "&attributes".len();
+        let block = self.bracket_expression(skip)?;
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::AndAttributes(block.src)),
+            skip + block.src.len() + 2,
+            block.src.matches('\n').count() as u32
+        )))
+    }
+
+    /// Self closing marker `/`
+    fn slash(&mut self) -> RuleResult<'a> {
+        match regex!(r"^/").find(self.get_input()) {
+            Some((0, end)) => Ok(Some(TokenResult::new(self.tok(TokenType::Slash), end, 0))),
+            _ => Ok(None)
+        }
+    }
+
+    /// Block expansion `:`, along with any following spaces
+    fn colon(&mut self) -> RuleResult<'a> {
+        match regex!(r"^: *").find(self.get_input()) {
+            Some((0, end)) => Ok(Some(TokenResult::new(self.tok(TokenType::Colon), end, 0))),
+            _ => Ok(None)
+        }
+    }
+
+    /// Line break, giving `Indent`, one or more `Outdent`s, or `Newline`
+    /// if the indentation is unchanged
+    ///
+    /// The indentation (tabs or spaces) is set by the first indented line.
+    /// In a pipeless text block only the indentation of the block is
+    /// consumed, so that any further indentation is kept in the text.
+    fn indent(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        let mut consume = match regex!(r"^\r?\n[ \t]*").find(input) {
+            Some((0, end)) => end,
+            _ => return Ok(None)
+        };
+        let whitespace = input[..consume].trim_start_matches(['\r', '\n']);
+        let break_len = consume - whitespace.len();
+        let mut indents = whitespace.len() as u32;
+        if self.position + consume == self.input.len() {
+            // trailing whitespace at the end of the input
+            return Ok(Some(TokenResult::new(self.tok(TokenType::Newline), consume, 1)));
+        }
+        let pipeless = self.pipeless && indents > self.last_indents;
+        if pipeless {
+            indents = cmp::min(indents, self.pipeless_indents.unwrap_or(indents));
+            consume -= whitespace.len() - indents as usize;
+        }
+        let whitespace = &whitespace[..indents as usize];
+        self.check_indentation(break_len, whitespace)?;
+
+        // only change state once the indentation is known to be valid
+        if self.indent_char.is_none() {
+            self.indent_char = whitespace.chars().next();
+        }
+        if pipeless {
+            self.pipeless_indents.get_or_insert(indents);
+        } else {
+            self.end_pipeless();
+            self.last_indents = indents;
+        }
+        let current = self.indent_stack.last().cloned().unwrap_or(0);
+        let token_type = if indents > current {
+            self.indent_stack.push(indents);
+            TokenType::Indent
+        } else if indents < current {
+            let mut outdents = 0;
+            while self.indent_stack.last().is_some_and(|&top| top > indents) {
+                self.indent_stack.pop();
+                outdents += 1;
+            }
+            for _ in 1..outdents {
+                let tok = self.tok(TokenType::Outdent);
+                self.defer(tok);
+            }
+            TokenType::Outdent
+        } else {
+            TokenType::Newline
+        };
+        Ok(Some(TokenResult::new(self.tok(token_type), consume, 1)))
+    }
+
+    /// Check the indentation of the next line, which follows a line break
+    /// of `break_len` bytes
+    fn check_indentation(&self, break_len: usize, whitespace: &str) -> Result<(), LexError> {
+        match self.indentation_error(whitespace) {
+            Some((idx, len, msg)) => Err(self.error_at(LexErrorKind::BadIndentation, break_len + idx, len, &msg)),
+            None => Ok(())
+        }
+    }
+
+    /// Check indentation only uses the indent character (or a single
+    /// character if this is the first indented line), and that a dedent
+    /// lines up with an enclosing level. An error gives the position and
+    /// length of the problem in the indentation, and a message
+    fn indentation_error(&self, whitespace: &str) -> Option<(usize, usize, String)> {
+        let indent_char = self.indent_char.or(whitespace.chars().next());
+        for (idx, ch) in whitespace.char_indices() {
+            if Some(ch) != indent_char {
+                return Some((idx, 1, format!(
+                    "invalid indentation, found a {} in indentation using {}",
+                    if ch == '\t' { "tab" } else { "space" },
+                    indent_name(indent_char)
+                )));
+            }
+        }
+        let indents = whitespace.len() as u32;
+        let mut above = None;
+        let below = self.indent_stack.iter().rev().cloned().find(|&level| {
+            if level > indents {
+                above = Some(level);
+            }
+            level <= indents
+        }).unwrap_or(0);
+        match above {
+            Some(above) if below != indents => Some((0, indents as usize, format!(
+                "inconsistent indentation, expected {} or {} {} but found {}",
+                below, above, indent_name(indent_char), indents
+            ))),
+            _ => None
+        }
+    }
+
+    /// A line of text in a pipeless text block
+    fn pipeless_text(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        if !self.pipeless || at_line_end(input) {
+            return Ok(None);
+        }
+        let line = &input[..line_len(input)];
+        if line.is_empty() {
+            // a `\r` at the end of the input, with no `\n` after it
+            return Ok(Some(TokenResult::new(self.tok(TokenType::Nothing), input.len(), 0)));
+        }
+        self.text_result(0, line, line.len())
+    }
+
+    /// Start of a pipeless text block, `.` at the end of a line
+    fn dot(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        match regex!(r"^\.[ \t]*").find(input) {
+            Some((0, end)) if at_line_end(&input[end..]) => {
+                self.start_pipeless();
+                Ok(Some(TokenResult::new(self.tok(TokenType::PipelessText), end, 0)))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    /// Text after a tag, or piped text `| text`
+    fn text(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        if !starts_with_any(input, b"| ") {
+            return Ok(None);
+        }
+        let (start, end) = match regex!(r"^(?:\| ?| )[^\n]*[^\r\n]").find(input) {
+            Some((0, end)) => {
+                // the space after `|` is only dropped if text follows it
+                (if input.starts_with("| ") && end > 2 { 2 } else { 1 }, end)
+            },
+            _ => match regex!(r"^(?:\| ?| )").find(input) {
+                Some((0, end)) => (end, end),
+                _ => return Ok(None)
+            }
+        };
+        self.text_result(start, &input[start..end], end)
+    }
+
+    /// Inline html, e.g. `<em>text</em>`
+    fn text_html(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        if !input.starts_with('<') {
+            return Ok(None);
+        }
+        let end = line_len(input);
+        self.text_result(0, &input[..end], end)
+    }
+
+    /// Create the result for some text `skip` bytes into the remaining
+    /// input, consuming `len` bytes, with any interpolations in it split
+    /// out into deferred tokens
+    fn text_result(&mut self, skip: usize, text: &'a str, len: usize) -> RuleResult<'a> {
+        // most text has no interpolations, so skip collecting the parts
+        if !regex!(r"[#!]\{|#\[").is_match(text) {
+            let tok = self.tok_at(TokenType::Text(Cow::Borrowed(text)), skip, text.len());
+            return Ok(Some(TokenResult::new(tok, len, 0)));
+        }
+        let mut parts = self.interpolate(skip, text)?.into_iter();
+        let first = parts.next().unwrap();
+        for part in parts {
+            self.defer(part);
+        }
+        Ok(Some(TokenResult::new(first, len, 0)))
+    }
+
+    /// Split text `skip` bytes into the remaining input into `Text`,
+    /// `Interpolation` and tag interpolation tokens, each with the span of
+    /// its own part of the text
+    ///
+    /// `\#{` and `\#[` are not interpolations, and are output as `#{`
+    /// and `#[`
+    ///
+    /// Errors, including those in a tag interpolation, are reported where
+    /// they are found in the line
+    fn interpolate(&self, skip: usize, text: &'a str) -> Result<Vec<Token<'a>>, LexError> {
+        let mut parts = Vec::new();
+        let mut buf = Cow::Borrowed("");
+        // where the text in `buf` starts in the remaining input
+        let mut buf_start = skip;
+        let mut rest = text;
+        let re = regex!(r"[#!]\{|#\[");
+        while let Some((start, end)) = re.find(rest) {
+            if rest[..start].ends_with('\\') {
+                append(&mut buf, &rest[..start - 1]);
+                append(&mut buf, &rest[start..end]);
+                rest = &rest[end..];
+                continue;
+            }
+            append(&mut buf, &rest[..start]);
+            // where `rest` starts in the remaining input
+            let at = skip + text.len() - rest.len();
+            let close = if rest[start..end].ends_with('[') { ']' } else { '}' };
+            let expr = match brackets::parse_max(&rest[end..]) {
+                Some(block) if rest[end + block.src.len()..].starts_with(close) => block.src,
+                _ => return Err(self.error_at(
+                    LexErrorKind::UnterminatedBracket,
+                    at + start,
+                    rest.len() - start,
+                    &format!("unterminated interpolation, expected {:?}", close)
+                ))
+            };
+            if !buf.is_empty() {
+                let text = mem::replace(&mut buf, Cow::Borrowed(""));
+                parts.push(self.tok_at(TokenType::Text(text), buf_start, at + start - buf_start));
+            }
+            if close == ']' {
+                parts.push(self.tok_at(TokenType::StartTagInterpolation, at + start, end - start));
+                let base = self.span_at(at + end, 0).start;
+                for tok in self.sub_lexer(expr) {
+                    match tok {
+                        Ok(Token { token_type: TokenType::EndOfSource, .. }) => {},
+                        Ok(mut tok) => {
+                            tok.span = Span {
+                                start: tok.span.start.within(base),
+                                end: tok.span.end.within(base)
+                            };
+                            parts.push(tok);
+                        },
+                        Err(err) => return Err(self.error_at(
+                            err.kind,
+                            at + end + err.span.start.offset,
+                            err.span.end.offset - err.span.start.offset,
+                            &err.message
+                        ))
+                    }
+                }
+                parts.push(self.tok_at(TokenType::EndTagInterpolation, at + end + expr.len(), 1));
+            } else {
+                parts.push(self.tok_at(TokenType::Interpolation {
+                    expr: expr.trim(),
+                    escaped: rest[start..].starts_with('#')
+                }, at + start, end + expr.len() + 1 - start));
+            }
+            rest = &rest[end + expr.len() + 1..];
+            buf_start = skip + text.len() - rest.len();
+        }
+        append(&mut buf, rest);
+        if !buf.is_empty() || parts.is_empty() {
+            parts.push(self.tok_at(TokenType::Text(buf), buf_start, skip + text.len() - buf_start));
+        }
+        Ok(parts)
+    }
+
+    /// A lexer for a part of the input on the current line, e.g. the
+    /// contents of a tag interpolation
+    fn sub_lexer<'b>(&self, input: &'b str) -> Lexer<'b> {
+        let mut lexer = Lexer::new_with_option(input, self.filename.clone());
+        lexer.line_number = self.line_number;
+        lexer
+    }
+
+    /// Nothing else matched, so the input is invalid
+    fn fail(&mut self) -> RuleResult<'a> {
+        let snippet: String = self.get_input().chars().take(5).collect();
+        let len = self.get_input().chars().next().map_or(0, |ch| ch.len_utf8());
+        Err(self.error_at(LexErrorKind::UnexpectedCharacter, 0, len, &format!("unexpected text {:?}", snippet)))
+    }
+
+    /// An error covering the rest of the current line
+    fn error(&self, kind: LexErrorKind, msg: &str) -> LexError {
+        let len = line_len(self.get_input());
+        self.error_at(kind, 0, len, msg)
+    }
+
+    /// An error covering `len` bytes, `skip` bytes into the remaining input
+    fn error_at(&self, kind: LexErrorKind, skip: usize, len: usize, msg: &str) -> LexError {
+        let span = self.span_at(skip, len);
+        let line_start = self.input[..span.start.offset].rfind('\n').map_or(0, |idx| idx + 1);
+        let line = &self.input[line_start..line_start + line_len(&self.input[line_start..])];
+        LexError {
+            kind,
+            message: msg.to_string(),
+            filename: self.filename.clone(),
+            span,
+            snippet: line.trim_start_matches('\u{feff}').to_string()
+        }
+    }
+
+    /// Doctype, e.g. `doctype html` or `!!! 5`
+    fn doctype(&mut self) -> RuleResult<'a> {
+        let input = self.get_input();
+        if !starts_with_any(input, b"!d") {
+            return Ok(None);
+        }
+        match regex!(r"^(?:!!!|doctype\b)[ \t]*").find(input) {
+            Some((0, end)) => {
+                let len = input.find('\n').unwrap_or(input.len());
+                let value = match input[end..len].trim() {
+                    "" => "default",
+                    value => value
+                };
+                Ok(Some(TokenResult::new(self.tok(TokenType::Doctype(value)), len, 0)))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    /// Interpolated tag name at the start of a line, e.g. `#{'h' + level}`
+    fn interpolation(&mut self) -> RuleResult<'a> {
+        if !self.get_input().starts_with("#{") {
+            return Ok(None);
+        }
+        let block = self.bracket_expression(1)?;
+        Ok(Some(TokenResult::new(
+            self.tok(TokenType::InterpolatedTag(block.src.trim())),
+            block.src.len() + 3,
+            block.src.matches('\n').count() as u32
+        )))
+    }
+
+}
+
+/// Iterates over the tokens, up to and including `EndOfSource` or the
+/// first error
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token<'a>, LexError>;
+
+    fn next(&mut self) -> Option<Result<Token<'a>, LexError>> {
+        if self.finished {
+            return None;
+        }
+        let res = Lexer::next(self);
+        match res {
+            Ok(Token { token_type: TokenType::EndOfSource, .. }) | Err(_) => {
+                self.finished = true;
+            },
+            Ok(_) => {}
+        }
+        Some(res)
+    }
+}
+
+/// The length in bytes of the expression at the start of `src`, which
+/// runs to the end of the line, or further while any brackets are open
+///
+/// A bracket or string left open at the end of the input is an error,
+/// giving its offset in `src`
+fn expression_len(src: &str) -> Result<usize, usize> {
+    let mut len = 0;
+    loop {
+        match brackets::parse_until_with_options(&src[len..], "\n", 0, true) {
+            Some(block) => { len += block.src.len(); },
+            None => {
+                return match unclosed(src) {
+                    Some(idx) => Err(idx),
+                    None => Ok(src.len())
+                };
+            }
+        }
+        match brackets::parse(&src[..len]) {
+            Some(ref state) if state.round_depth > 0
+                    || state.curly_depth > 0
+                    || state.square_depth > 0 => { len += 1; },
+            _ => { return Ok(len); }
+        }
+    }
+}
+
+/// The offset of the outermost bracket or quote that `src` leaves open,
+/// if any
+fn unclosed(src: &str) -> Option<usize> {
+    let depth = |state: &BracketState| state.round_depth + state.curly_depth + state.square_depth;
+    let mut state: BracketState = Default::default();
+    let mut open = Vec::new();
+    for (idx, ch) in src.char_indices() {
+        let (was_string, was_depth) = (state.in_string(), depth(&state));
+        brackets::parse_char_from_state(ch, &mut state);
+        if state.in_string() != was_string {
+            if was_string { open.pop(); } else { open.push(idx); }
+        } else if depth(&state) > was_depth {
+            open.push(idx);
+        } else if depth(&state) < was_depth {
+            open.pop();
+        }
+    }
+    if state.in_string() || depth(&state) > 0 {
+        open.first().cloned()
+    } else {
+        None
+    }
+}
+
+/// Whether `src` is at a line break (`\n` or `\r\n`), or the end of input
+fn at_line_end(src: &str) -> bool {
+    src.is_empty() || src.starts_with('\n') || src.starts_with("\r\n")
+}
+
+/// The length in bytes of the first line of `src`, without its line break
+fn line_len(src: &str) -> usize {
+    let len = src.find('\n').unwrap_or(src.len());
+    if src[..len].ends_with('\r') { len - 1 } else { len }
+}
+
+/// The extension of the file named by `path`, e.g. `md` for
+/// `./docs/readme.md`, or `None` if it has none
+fn extension(path: &str) -> Option<&str> {
+    let name = path.rsplit('/').next().unwrap();
+    match name.rfind('.') {
+        Some(idx) if idx > 0 && idx + 1 < name.len() => Some(&name[idx + 1..]),
+        _ => None
+    }
+}
+
+/// Whether `src` starts with one of `bytes`
+///
+/// Rules check this before trying a regex with captures, as capturing
+/// allocates even when there is no match
+fn starts_with_any(src: &str, bytes: &[u8]) -> bool {
+    src.as_bytes().first().is_some_and(|byte| bytes.contains(byte))
+}
+
+/// The name of an indent character, for error messages
+fn indent_name(indent_char: Option<char>) -> &'static str {
+    match indent_char {
+        Some('\t') => "tabs",
+        _ => "spaces"
+    }
+}
+
+/// Add some text to a text buffer, which only allocates once the text
+/// isn't a single slice of the input
+fn append<'a>(buf: &mut Cow<'a, str>, text: &'a str) {
+    if buf.is_empty() {
+        *buf = Cow::Borrowed(text);
+    } else if !text.is_empty() {
+        buf.to_mut().push_str(text);
+    }
+}
+
+/// Split a list of arguments or parameters on the commas that aren't
+/// nested in brackets or strings
+fn split_args(src: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let mut state: BracketState = Default::default();
+    let mut start = 0;
+    for (idx, ch) in src.char_indices() {
+        if ch == ',' && !state.in_nesting() {
+            args.push(src[start..idx].trim());
+            start = idx + 1;
+        } else {
+            brackets::parse_char_from_state(ch, &mut state);
+        }
+    }
+    args.push(src[start..].trim());
+    // allow a trailing comma
+    if args.last().is_some_and(|arg| arg.is_empty()) {
+        args.pop();
+    }
+    args
+}
+
+/// Split the contents of an attribute block into attributes
+///
+/// Attributes are separated by commas or newlines, or by whitespace where
+/// the value expression is clearly complete. A value without a name, or
+/// `=` without a value, is an error
+fn parse_attributes(src: &str) -> Result<Vec<Attribute<'_>>, String> {
+    let mut chars = src.char_indices();
+    let mut attrs = Vec::new();
+    // the name and value are kept as ranges of `src`
+    let mut name = 0..0;
+    let mut value = 0..0;
+    let mut quote: Option<char> = None;
+    let mut in_value = false;
+    let mut escaped = true;
+    let mut state: BracketState = Default::default();
+
+    while let Some((idx, ch)) = chars.next() {
+        if !in_value {
+            match (quote, ch) {
+                (Some(q), ch) if ch == q => { quote = None; },
+                (Some(_), ch) => { extend(&mut name, idx, ch); },
+                (None, '\'') | (None, '"') if name.is_empty() => { quote = Some(ch); },
+                (None, ',') | (None, '\n') => {
+                    push_attribute(&mut attrs, src, &mut name, None, true)?;
+                },
+                (None, ' ') | (None, '\t') | (None, '\r') => {
+                    // the name ends here unless a value follows
+                    match next_non_whitespace(&src[idx + 1..]) {
+                        Some('=') | Some('!') => {},
+                        _ => { push_attribute(&mut attrs, src, &mut name, None, true)?; }
+                    }
+                },
+                (None, '=') if name.is_empty() => {
+                    return Err("attribute value without a name".to_string());
+                },
+                (None, '!') if src[idx + 1..].starts_with('=') => {
+                    if name.is_empty() {
+                        return Err("attribute value without a name".to_string());
+                    }
+                    in_value = true;
+                    escaped = false;
+                    chars.next();
+                },
+                (None, '=') => { in_value = true; },
+                (None, ch) => { extend(&mut name, idx, ch); }
+            }
+        } else {
+            let separator = !state.in_nesting() && match ch {
+                ',' | '\n' => true,
+                ' ' | '\t' => !value.is_empty() && value_complete(&src[value.clone()], &src[idx + 1..]),
+                _ => false
+            };
+            if separator {
+                if value.is_empty() {
+                    return Err(format!("missing value for attribute {:?}", &src[name]));
+                }
+                let expr = interpolate_attribute(src[value].trim_end());
+                push_attribute(&mut attrs, src, &mut name, Some(expr), escaped)?;
+                value = 0..0;
+                in_value = false;
+                escaped = true;
+                state = Default::default();
+            } else if !value.is_empty() || !ch.is_whitespace() {
+                brackets::parse_char_from_state(ch, &mut state);
+                extend(&mut value, idx, ch);
+            }
+        }
+    }
+    if in_value {
+        if src[value.clone()].trim_end().is_empty() {
+            return Err(format!("missing value for attribute {:?}", &src[name]));
+        }
+        let expr = interpolate_attribute(src[value].trim_end());
+        push_attribute(&mut attrs, src, &mut name, Some(expr), escaped)?;
+    } else {
+        push_attribute(&mut attrs, src, &mut name, None, true)?;
+    }
+    Ok(attrs)
+}
+
+/// Extend a range of the source to take in the char `ch` at `idx`
+fn extend(range: &mut Range<usize>, idx: usize, ch: char) {
+    if (*range).is_empty() {
+        range.start = idx;
+    }
+    range.end = idx + ch.len_utf8();
+}
+
+/// Add an attribute, if it has a name, clearing the name range
+///
+/// A quoted name, e.g. `'(click)'`, may hold anything, but otherwise it
+/// has to be an identifier, such as `data-id` or `:value`
+fn push_attribute<'a>(attrs: &mut Vec<Attribute<'a>>,
+                      src: &'a str,
+                      name: &mut Range<usize>,
+                      value: Option<Cow<'a, str>>,
+                      escaped: bool) -> Result<(), String> {
+    if !(*name).is_empty() {
+        let text = &src[name.clone()];
+        let quoted = src[..name.start].ends_with(['\'', '"']);
+        if !quoted && !text.chars().all(|ch| ch.is_alphanumeric() || "_-:.@$".contains(ch)) {
+            return Err(format!("invalid attribute name {:?}", text));
+        }
+        attrs.push(Attribute::new(text, value, escaped));
+        *name = 0..0;
+    }
+    Ok(())
+}
+
+/// Turn `#{}` interpolations in a quoted attribute string into string
+/// concatenation, e.g. `"a#{b}"` becomes `"a" + (b) + ""`
+///
+/// Anything other than a single string literal is left as it is, as are
+/// escaped (`\#{`) and unterminated interpolations
+fn interpolate_attribute(value: &str) -> Cow<'_, str> {
+    if !is_string_literal(value) || !value.contains("#{") {
+        return Cow::Borrowed(value);
+    }
+    let quote = &value[..1];
+    let mut res = String::new();
+    let mut rest = value;
+    let re = regex!(r"#\{");
+    while let Some((start, end)) = re.find(rest) {
+        res.push_str(&rest[..start]);
+        match brackets::parse_max(&rest[end..]) {
+            Some(ref block) if !rest[..start].ends_with('\\')
+                    && rest[end + block.src.len()..].starts_with('}') => {
+                res.push_str(&format!("{} + ({}) + {}", quote, block.src, quote));
+                rest = &rest[end + block.src.len() + 1..];
+            },
+            _ => {
+                res.push_str(&rest[start..end]);
+                rest = &rest[end..];
+            }
+        }
+    }
+    res.push_str(rest);
+    Cow::Owned(res)
+}
+
+/// Whether the expression is exactly one quoted string
+fn is_string_literal(value: &str) -> bool {
+    if !value.starts_with('\'') && !value.starts_with('"') {
+        return false;
+    }
+    let mut state: BracketState = Default::default();
+    for (idx, ch) in value.char_indices() {
+        brackets::parse_char_from_state(ch, &mut state);
+        if idx > 0 && !state.in_string() {
+            return idx + ch.len_utf8() == value.len();
+        }
+    }
+    false
+}
+
+/// The first char of `src` that isn't whitespace
+fn next_non_whitespace(src: &str) -> Option<char> {
+    src.chars().find(|ch| !ch.is_whitespace())
+}
+
+/// Whether an attribute value is complete at whitespace followed by
+/// `rest`, i.e. it doesn't end in, and isn't followed by, an operator.
+/// Keywords such as `typeof` or `instanceof` are operators too
+fn value_complete(value: &str, rest: &str) -> bool {
+    let continues = |ch: char| {
+        brackets::is_punctuator(ch) && ch != ')' && ch != ']' && ch != '}'
+    };
+    let is_word_char = |ch: char| ch.is_alphanumeric() || ch == '_' || ch == '$';
+    let value = value.trim_end();
+    let last_word = value.rsplit(|ch| !is_word_char(ch)).next().unwrap();
+    let next_word = rest.trim_start().split(|ch| !is_word_char(ch)).next().unwrap();
+    match value.chars().last() {
+        Some(ch) if continues(ch) => false,
+        // `this` and `super` are values rather than operators
+        _ if brackets::is_keyword(last_word) && last_word != "this" && last_word != "super" => false,
+        _ if next_word == "in" || next_word == "instanceof" => false,
+        _ => match next_non_whitespace(rest) {
+            Some('(') | Some('[') | Some('{') => true,
+            Some(ch) => !continues(ch),
+            None => true
+        }
+    }
+}
+
+impl <'a> fmt::Debug for Lexer<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Lexer {{ input: {}, position: {} }}", self.input, self.position)
+    }
+}
+
+// ======================
+//
+// Tests
+//
+// ======================
+
+#[cfg(test)]
+mod tests {
+    use lexer::{Attribute, BlockType, Filter, LexError, LexErrorKind, Position, Span, Token, TokenType, TokenResult, Lexer};
+    use regex;
+    use std::borrow::Cow;
+    use std::collections::VecDeque;
+
+    fn jade_block<'a>() -> &'a str {
+        concat!(
+            "doctype html\n",
+            "html(lang=\"en\")\n",
+            "  head\n",
+            "    title= pageTitle\n",
+            "    script(type='text/javascript').\n",
+            "      if (foo) {\n",
+            "         bar(1 + 5)\n",
+            "      }\n",
+            "  body\n",
+            "    h1 Jade - node template engine\n",
+            "    #container.col\n",
+            "      if youAreUsingJade\n",
+            "        p You are amazing\n",
+            "      else\n",
+            "        p Get on it!\n",
+            "      p.\n",
+            "        Jade is a terse and simple\n",
+            "        templating language with a\n",
+            "        strong focus on performance\n",
+            "        and powerful features.\n")
+    }
+
+    #[test]
+    fn new() {
+        let ls = Lexer::new(jade_block());
+        assert_eq!(ls, Lexer {
+                input: jade_block(),
+                filename: None,
+                position: 0,
+                deferred_tokens: VecDeque::new(),
+                last_indents: 0,
+                line_number: 1,
+                stash: VecDeque::new(),
+                indent_stack: Vec::new(),
+                indent_char: None,
+                pipeless: false,
+                pipeless_indents: None,
+                finished: false,
+                location: Position { offset: 0, line: 1, column: 1 }
+        })
+    }
+
+    #[test]
+    fn consume() {
+        let test_str = "function testfn() { }";
+        let mut ls = Lexer::new(test_str);
+        assert_eq!(ls.consume(10), "function t");
+        assert_eq!(ls.consume(5), "estfn");
+        assert_eq!(ls.position, 15);
+    }
+
+    #[test]
+    fn lookahead() {
+        let test_str = "// one\n// two\n";
+        let mut ls = Lexer::new(test_str);
+        assert_eq!(kind(ls.lookahead(2).unwrap()), (&TokenType::Newline, 1));
+        assert_eq!(kind(ls.lookahead(3).unwrap()), (
+            &TokenType::Comment(Some(" two"), true), 2
+        ));
+        // looking ahead doesn't consume
+        assert_eq!(kind(&ls.next().unwrap()), (
+            &TokenType::Comment(Some(" one"), true), 1
+        ));
+        assert_eq!(kind(&ls.next().unwrap()), (&TokenType::Newline, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "lookahead starts at 1")]
+    fn lookahead_zero() {
+        let _ = Lexer::new("p").lookahead(0);
+    }
+
+    #[test]
+    fn peek() {
+        let mut ls = Lexer::new("äö 日本");
+        assert_eq!(ls.peek(), Some('ä'));
+        ls.consume(5);
+        assert_eq!(ls.peek(), Some('日'));
+        ls.consume(6);
+        assert_eq!(ls.peek(), None);
+    }
+
+    // TODO use token-like strings to test
+    #[test]
+    fn scan_regex() {
+        let test_str = "function testfn() { }";
+        let mut ls = Lexer::new(test_str);
+        // first regex should match "function" and capture "unc"
+        let re = regex::Regex::new(r"[fg](unc)tion").unwrap();
+        assert_eq!(ls.scan_regex(re), Some("unc"));
+        // second regex should fail
+        let re2 = regex::Regex::new(r" ?(\(\)) ").unwrap();
+        assert_eq!(ls.scan_regex(re2), None);
+        // third regex should match " testfn" and capture "testfn"
+        let re3 = regex::Regex::new(r" ?(t?e?s?t?t?f?n+)").unwrap();
+        assert_eq!(ls.scan_regex(re3), Some("testfn"));
+    }
+
+    #[test]
+    fn eos() {
+        let mut true1 = Lexer::new("");
+        let mut false1 = Lexer::new("notend");
+        assert_eq!(true1.eos(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::EndOfSource), 0, 0
+        ))));
+        assert_eq!(false1.eos(), Ok(None));
+    }
+
+    #[test]
+    fn blank() {
+        let mut true1 = Lexer::new("\n        \n");
+        let mut true2 = Lexer::new("\n\n");
+        let mut false1 = Lexer::new("\nSome text this line\n");
+        assert_eq!(true1.blank(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Nothing), 9, 1
+        ))));
+        assert_eq!(true2.blank(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::Nothing), 1, 1
+        ))));
+        assert_eq!(false1.blank(), Ok(None));
+    }
+
+    #[test]
+    fn comment() {
+        let mut true1 = Lexer::new("// This is a comment\nThis is another line");
+        let mut true2 = Lexer::new("//- This is an unbuffered comment");
+        let mut false1 = Lexer::new("This is not a comment // this is not the next token");
+        assert_eq!(true1.comment(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Comment(Some(" This is a comment"), true)),
+            "// This is a comment".len(),
+            0
+        ))));
+        assert_eq!(true2.comment(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Comment(
+                Some(" This is an unbuffered comment"),
+                false
+            )),
+            "//- This is an unbuffered comment".len(),
+            0
+        ))));
+        assert_eq!(false1.comment(), Ok(None));
+    }
+
+    #[test]
+    fn tag() {
+        let mut true1 = Lexer::new("html(lang=\"en\")");
+        let mut true2 = Lexer::new("svg:rect");
+        let mut true3 = Lexer::new("my-element/");
+        let mut false1 = Lexer::new("-element");
+        assert_eq!(true1.tag(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Tag("html")), 4, 0
+        ))));
+        assert_eq!(true2.tag(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::Tag("svg:rect")), 8, 0
+        ))));
+        assert_eq!(true3.tag(), Ok(Some(TokenResult::new(
+            true3.tok(TokenType::Tag("my-element")), 10, 0
+        ))));
+        assert_eq!(false1.tag(), Ok(None));
+    }
+
+    fn lex_error(mut ls: Lexer) -> LexError {
+        ls.find_map(|tok| tok.err()).unwrap()
+    }
+
+    fn kind<'a, 'b>(tok: &'b Token<'a>) -> (&'b TokenType<'a>, u32) {
+        (tok.token_type(), tok.line_number())
+    }
+
+    fn token_types(src: &str) -> Vec<TokenType<'_>> {
+        Lexer::new(src).map(|tok| tok.unwrap().token_type).collect()
+    }
+
+    fn code(buffered: bool, escaped: bool, expr: &str) -> TokenType<'_> {
+        TokenType::Code { buffered, escaped, expr: expr.into() }
+    }
+
+    #[test]
+    fn code_rule() {
+        let mut true1 = Lexer::new("= pageTitle\np");
+        let mut true2 = Lexer::new("!=  html");
+        let mut true3 = Lexer::new("- var x = foo(1,\n  2) // (\np");
+        let mut false1 = Lexer::new("p= pageTitle");
+        assert_eq!(true1.code(), Ok(Some(TokenResult::new(
+            true1.tok(code(true, true, "pageTitle")), 11, 0
+        ))));
+        assert_eq!(true2.code(), Ok(Some(TokenResult::new(
+            true2.tok(code(true, false, "html")), 8, 0
+        ))));
+        assert_eq!(true3.code(), Ok(Some(TokenResult::new(
+            true3.tok(code(false, false, "var x = foo(1,\n  2) // (")), 26, 1
+        ))));
+        assert_eq!(false1.code(), Ok(None));
+    }
+
+    #[test]
+    fn code_without_expression() {
+        let mut ls = Lexer::new("=\n");
+        let err = ls.code().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::Malformed);
+        assert_eq!(err.message, "expected an expression after \"=\"");
+    }
+
+    #[test]
+    fn block_code() {
+        let src = "-\n  var list = [1, 2]\n\n  if (a)\n    b()\n\np";
+        let mut true1 = Lexer::new(src);
+        let mut false1 = Lexer::new("- var x");
+        assert_eq!(true1.block_code(), Ok(Some(TokenResult::new(
+            true1.tok(code(false, false, "var list = [1, 2]\n\nif (a)\n  b()")),
+            src.len() - 3,
+            4
+        ))));
+        assert_eq!(false1.block_code(), Ok(None));
+    }
+
+    #[test]
+    fn block_code_empty() {
+        for src in &["-\n", "-\np", "-\n\n  \np"] {
+            let err = lex_error(Lexer::new(src));
+            assert_eq!(err.kind, LexErrorKind::Malformed);
+            assert_eq!(err.message, "expected an expression after \"-\"");
+        }
+    }
+
+    #[test]
+    fn block_code_crlf() {
+        assert_eq!(token_types("-\r\n  x = 1\r\n  y = ä"), vec![
+            code(false, false, "x = 1\ny = ä"),
+            TokenType::EndOfSource
+        ]);
+        assert_eq!(token_types("-\r\n\t}if ä"), vec![
+            code(false, false, "}if ä"),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn code_tokens() {
+        let src = "title= pageTitle\n-\n  x = 1\np!= x\n- y()";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Tag("title"),
+            code(true, true, "pageTitle"),
+            TokenType::Newline,
+            code(false, false, "x = 1"),
+            TokenType::Newline,
+            TokenType::Tag("p"),
+            code(true, false, "x"),
+            TokenType::Newline,
+            code(false, false, "y()"),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn case() {
+        let mut true1 = Lexer::new("case friends.length\n");
+        let mut false1 = Lexer::new("cases");
+        assert_eq!(true1.case(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Case("friends.length")), 19, 0
+        ))));
+        assert_eq!(false1.case(), Ok(None));
+    }
+
+    #[test]
+    fn when() {
+        let mut true1 = Lexer::new("when 'a': p A");
+        let mut true2 = Lexer::new("when {a: 1}.a\n");
+        let mut false1 = Lexer::new("whenever");
+        assert_eq!(true1.when(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::When("'a'")), 8, 0
+        ))));
+        assert_eq!(true2.when(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::When("{a: 1}.a")), 13, 0
+        ))));
+        assert_eq!(false1.when(), Ok(None));
+    }
+
+    #[test]
+    fn when_without_expression() {
+        let mut ls = Lexer::new("when: p");
+        let err = ls.when().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::Malformed);
+        assert_eq!(err.message, "expected an expression after \"when\"");
+    }
+
+    #[test]
+    fn case_tokens() {
+        let src = "case n\n  when 0\n  when 1: p one\n  default\n    p many";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Case("n"),
+            TokenType::Indent,
+            TokenType::When("0"),
+            TokenType::Newline,
+            TokenType::When("1"),
+            TokenType::Colon,
+            TokenType::Tag("p"),
+            TokenType::Text("one".into()),
+            TokenType::Newline,
+            TokenType::Default,
+            TokenType::Indent,
+            TokenType::Tag("p"),
+            TokenType::Text("many".into()),
+            TokenType::Outdent,
+            TokenType::Outdent,
+            TokenType::EndOfSource
+        ]);
+    }
+
+    fn block(name: &str, block_type: BlockType) -> TokenType<'_> {
+        TokenType::Block { name, block_type }
+    }
+
+    #[test]
+    fn extends() {
+        let mut true1 = Lexer::new("extends ../layout.jade\n");
+        let mut true2 = Lexer::new("extend layout");
+        let mut false1 = Lexer::new("extender");
+        assert_eq!(true1.extends(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Extends("../layout.jade")), 22, 0
+        ))));
+        assert_eq!(true2.extends(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::Extends("layout")), 13, 0
+        ))));
+        assert_eq!(false1.extends(), Ok(None));
+    }
+
+    #[test]
+    fn blocks() {
+        let src = "block content\nblock append scripts\nblock prepend head\nappend scripts\n\
+                   prepend head \nblock\nblockquote";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            block("content", BlockType::Replace),
+            TokenType::Newline,
+            block("scripts", BlockType::Append),
+            TokenType::Newline,
+            block("head", BlockType::Prepend),
+            TokenType::Newline,
+            block("scripts", BlockType::Append),
+            TokenType::Newline,
+            block("head", BlockType::Prepend),
+            TokenType::Newline,
+            TokenType::MixinBlock,
+            TokenType::Newline,
+            TokenType::Tag("blockquote"),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn block_without_name() {
+        let mut ls = Lexer::new("append\n");
+        let err = ls.append().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::Malformed);
+        assert_eq!(err.message, "missing block name");
+    }
+
+    #[test]
+    fn include() {
+        let mut true1 = Lexer::new("include ./includes/head.jade\n");
+        let mut true2 = Lexer::new("include:markdown ./readme.md");
+        let mut true3 = Lexer::new("include:coffee(bare=true):uglify-js file.coffee");
+        let mut true4 = Lexer::new("include style.css");
+        let mut true5 = Lexer::new("include ./mixins/buttons");
+        let mut false1 = Lexer::new("includes");
+        assert_eq!(true1.include(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Include {
+                path: "./includes/head.jade",
+                filters: vec![],
+                raw: false
+            }),
+            28,
+            0
+        ))));
+        assert_eq!(true2.include(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::Include {
+                path: "./readme.md",
+                filters: vec![Filter::new("markdown", vec![])],
+                raw: false
+            }),
+            28,
+            0
+        ))));
+        assert_eq!(true3.include(), Ok(Some(TokenResult::new(
+            true3.tok(TokenType::Include {
+                path: "file.coffee",
+                filters: vec![
+                    Filter::new("coffee", vec![attr("bare", Some("true"), true)]),
+                    Filter::new("uglify-js", vec![])
+                ],
+                raw: false
+            }),
+            47,
+            0
+        ))));
+        assert_eq!(true4.include(), Ok(Some(TokenResult::new(
+            true4.tok(TokenType::Include {
+                path: "style.css",
+                filters: vec![],
+                raw: true
+            }),
+            17,
+            0
+        ))));
+        // jade adds `.jade` to a path without an extension
+        assert_eq!(true5.include(), Ok(Some(TokenResult::new(
+            true5.tok(TokenType::Include {
+                path: "./mixins/buttons",
+                filters: vec![],
+                raw: false
+            }),
+            24,
+            0
+        ))));
+        assert_eq!(false1.include(), Ok(None));
+    }
+
+    #[test]
+    fn include_without_path() {
+        let mut ls = Lexer::new("include:markdown\n");
+        let err = ls.include().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::Malformed);
+        assert_eq!(err.message, "missing path for include");
+    }
+
+    fn filter<'a>(name: &'a str, options: Vec<Attribute<'a>>) -> TokenType<'a> {
+        TokenType::Filter { name, options }
+    }
+
+    #[test]
+    fn text_filter() {
+        let mut true1 = Lexer::new(":markdown\n  # Title");
+        let mut true2 = Lexer::new(":uglify-js:coffee(bare=true, x) \n  a");
+        let mut false1 = Lexer::new(": p");
+        assert_eq!(true1.text_filter(), Ok(Some(TokenResult::new(
+            true1.tok(filter("markdown", vec![])), 9, 0
+        ))));
+        assert!(true1.pipeless);
+        assert_eq!(true2.text_filter(), Ok(Some(TokenResult::new(
+            true2.tok(filter("uglify-js", vec![])), 32, 0
+        ))));
+        assert_eq!(true2.deferred(), Some(true2.tok(filter("coffee", vec![
+            attr("bare", Some("true"), true),
+            attr("x", None, true)
+        ]))));
+        assert_eq!(false1.text_filter(), Ok(None));
+    }
+
+    #[test]
+    fn filter_tokens() {
+        let src = "script\n  :cdata\n    if (a < b)\n\n      c()\np";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Tag("script"),
+            TokenType::Indent,
+            filter("cdata", vec![]),
+            TokenType::Indent,
+            TokenType::Text("if (a < b)".into()),
+            TokenType::Text("".into()),
+            TokenType::Newline,
+            TokenType::Text("  c()".into()),
+            TokenType::Outdent,
+            TokenType::Outdent,
+            TokenType::Tag("p"),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn mixin() {
+        let mut true1 = Lexer::new("mixin list(id, ...items)\n");
+        let mut true2 = Lexer::new("mixin nav-item");
+        let mut false1 = Lexer::new("mixins");
+        assert_eq!(true1.mixin(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Mixin {
+                name: "list",
+                params: vec!["id", "...items"]
+            }),
+            24,
+            0
+        ))));
+        assert_eq!(true2.mixin(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::Mixin { name: "nav-item", params: vec![] }),
+            14,
+            0
+        ))));
+        assert_eq!(false1.mixin(), Ok(None));
+    }
+
+    #[test]
+    fn call() {
+        let mut true1 = Lexer::new("+list('x', [1, 2], {a: 1, b: f(2, 3)}, \",\")(class=\"c\")");
+        let mut true2 = Lexer::new("+ #{name + 'Item'}()");
+        let mut true3 = Lexer::new("+link(href=\"/\")");
+        let mut false1 = Lexer::new("list()");
+        assert_eq!(true1.call(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Call {
+                name: "list",
+                args: vec!["'x'", "[1, 2]", "{a: 1, b: f(2, 3)}", "\",\""]
+            }),
+            43,
+            0
+        ))));
+        assert_eq!(true2.call(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::Call {
+                name: "#{name + 'Item'}",
+                args: vec![]
+            }),
+            20,
+            0
+        ))));
+        assert_eq!(true3.call(), Ok(Some(TokenResult::new(
+            true3.tok(TokenType::Call { name: "link", args: vec![] }),
+            5,
+            0
+        ))));
+        assert_eq!(false1.call(), Ok(None));
+    }
+
+    #[test]
+    fn mixin_tokens() {
+        let src = "mixin item(x)\n  li= x\n+item(1)(class=\"c\")";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Mixin { name: "item", params: vec!["x"] },
+            TokenType::Indent,
+            TokenType::Tag("li"),
+            code(true, true, "x"),
+            TokenType::Outdent,
+            TokenType::Call { name: "item", args: vec!["1"] },
+            TokenType::Attrs(vec![attr("class", Some("\"c\""), true)]),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn conditional() {
+        let mut true1 = Lexer::new("if youAreUsingJade\n  p");
+        let mut true2 = Lexer::new("else  if (a ||\n    b) && c\n");
+        let mut true3 = Lexer::new("else\n");
+        let mut true4 = Lexer::new("- if (foo(a) && b)");
+        let mut true5 = Lexer::new("unless user.isAnonymous");
+        let mut false1 = Lexer::new("iframe");
+        assert_eq!(true1.conditional(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::If("youAreUsingJade")), 18, 0
+        ))));
+        assert_eq!(true2.conditional(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::ElseIf("(a ||\n    b) && c")), 26, 1
+        ))));
+        assert_eq!(true3.conditional(), Ok(Some(TokenResult::new(
+            true3.tok(TokenType::Else), 4, 0
+        ))));
+        assert_eq!(true4.conditional(), Ok(Some(TokenResult::new(
+            true4.tok(TokenType::If("foo(a) && b")), 18, 0
+        ))));
+        assert_eq!(true5.conditional(), Ok(Some(TokenResult::new(
+            true5.tok(TokenType::Unless("user.isAnonymous")), 23, 0
+        ))));
+        assert_eq!(false1.conditional(), Ok(None));
+    }
+
+    #[test]
+    fn conditional_without_condition() {
+        let mut ls = Lexer::new("if\n  p");
+        let err = ls.conditional().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::Malformed);
+        assert_eq!(err.to_string(), "line 1, column 1: expected a condition after \"if\"");
+        assert_eq!(err.snippet, "if");
+    }
+
+    #[test]
+    fn else_with_condition() {
+        let mut ls = Lexer::new("else foo");
+        let err = ls.conditional().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::Malformed);
+        assert_eq!(err.message, "unexpected condition \"foo\" after else");
+    }
+
+    #[test]
+    fn conditional_tokens() {
+        let src = "if a\n  p A\nelse if b\n  p B\nelse: p C";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::If("a"),
+            TokenType::Indent,
+            TokenType::Tag("p"),
+            TokenType::Text("A".into()),
+            TokenType::Outdent,
+            TokenType::ElseIf("b"),
+            TokenType::Indent,
+            TokenType::Tag("p"),
+            TokenType::Text("B".into()),
+            TokenType::Outdent,
+            TokenType::Else,
+            TokenType::Colon,
+            TokenType::Tag("p"),
+            TokenType::Text("C".into()),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    fn each<'a>(value: &'a str, key: Option<&'a str>, expr: &'a str) -> TokenType<'a> {
+        TokenType::Each { value, key, expr }
+    }
+
+    #[test]
+    fn each_rule() {
+        let mut true1 = Lexer::new("each item, idx in items\n");
+        let mut true2 = Lexer::new("for val , key in {a: 1,\n  b: 2}");
+        let mut true3 = Lexer::new("- each $x in [1, 2]");
+        let mut false1 = Lexer::new("eachItem");
+        assert_eq!(true1.each(), Ok(Some(TokenResult::new(
+            true1.tok(each("item", Some("idx"), "items")), 23, 0
+        ))));
+        assert_eq!(true2.each(), Ok(Some(TokenResult::new(
+            true2.tok(each("val", Some("key"), "{a: 1,\n  b: 2}")), 31, 1
+        ))));
+        assert_eq!(true3.each(), Ok(Some(TokenResult::new(
+            true3.tok(each("$x", None, "[1, 2]")), 19, 0
+        ))));
+        assert_eq!(false1.each(), Ok(None));
+        assert_eq!(Lexer::new("- for (var i = 0; i < 3; i++)").each(), Ok(None));
+    }
+
+    #[test]
+    fn each_malformed() {
+        let err = lex_error(Lexer::new("ul\neach in items\n  li"));
+        assert_eq!(err.kind, LexErrorKind::Malformed);
+        assert_eq!(err.to_string(), "line 2, column 1: malformed each \"each in items\"");
+    }
+
+    #[test]
+    fn each_else() {
+        let src = "each item in items\n  li= item\nelse\n  li none";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            each("item", None, "items"),
+            TokenType::Indent,
+            TokenType::Tag("li"),
+            code(true, true, "item"),
+            TokenType::Outdent,
+            TokenType::Else,
+            TokenType::Indent,
+            TokenType::Tag("li"),
+            TokenType::Text("none".into()),
+            TokenType::Outdent,
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn while_loop() {
+        let mut true1 = Lexer::new("while n < 4\n  li= n++");
+        let mut false1 = Lexer::new("whilst");
+        assert_eq!(true1.while_loop(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::While("n < 4")), 11, 0
+        ))));
+        assert_eq!(false1.while_loop(), Ok(None));
+    }
+
+    #[test]
+    fn while_without_condition() {
+        let mut ls = Lexer::new("while\n");
+        let err = ls.while_loop().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::Malformed);
+        assert_eq!(err.message, "expected a condition after \"while\"");
+    }
+
+    #[test]
+    fn unterminated_expression() {
+        let cases = [
+            ("if (a\n  p\ndiv", 3, "unterminated bracket, expected ')'"),
+            ("= foo(1,\n  2\np", 5, "unterminated bracket, expected ')'"),
+            ("each x in [1,2\nli", 10, "unterminated bracket, expected ']'"),
+            ("while {a: 1\np", 6, "unterminated bracket, expected '}'"),
+            ("case (a\nwhen 1", 5, "unterminated bracket, expected ')'"),
+            ("when f(a\n  p", 6, "unterminated bracket, expected ')'"),
+            ("- x = 'a\np", 6, "unterminated string, expected '\\''")
+        ];
+        for &(src, start, msg) in cases.iter() {
+            let err = lex_error(Lexer::new(src));
+            assert_eq!(err.kind, LexErrorKind::UnterminatedBracket, "{:?}", src);
+            assert_eq!(err.message, msg, "{:?}", src);
+            assert_eq!(err.span.start.offset, start, "{:?}", src);
+            assert_eq!(err.span.end.offset, src.find('\n').unwrap(), "{:?}", src);
+        }
+        // closed on a later line is fine
+        assert_eq!(token_types("if (a\n  || b)\n  p")[0], TokenType::If("(a\n  || b)"));
+    }
+
+    #[test]
+    fn id() {
+        let mut true1 = Lexer::new("#container.col");
+        let mut false1 = Lexer::new("#{name}");
+        assert_eq!(true1.id(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Id("container")), 10, 0
+        ))));
+        assert_eq!(false1.id(), Ok(None));
+    }
+
+    #[test]
+    fn class_name() {
+        let mut true1 = Lexer::new(".col.-x");
+        let mut true2 = Lexer::new(".-x");
+        let mut false1 = Lexer::new(".\n");
+        assert_eq!(true1.class_name(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Class("col")), 4, 0
+        ))));
+        assert_eq!(true2.class_name(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::Class("-x")), 3, 0
+        ))));
+        assert_eq!(false1.class_name(), Ok(None));
+    }
+
+    #[test]
+    fn invalid_class_name() {
+        let err = lex_error(Lexer::new_with_filename("p\n.1foo", "test.jade".to_string()));
+        assert_eq!(err.kind, LexErrorKind::InvalidAttribute);
+        assert_eq!(err.filename, Some("test.jade".to_string()));
+        assert_eq!(err.to_string(), "test.jade:2:1: invalid class name \".1foo\"");
+    }
+
+    #[test]
+    fn shorthands() {
+        let tokens = token_types("#container.col\na#home.nav.active");
+        assert_eq!(tokens, vec![
+            TokenType::Id("container"),
+            TokenType::Class("col"),
+            TokenType::Newline,
+            TokenType::Tag("a"),
+            TokenType::Id("home"),
+            TokenType::Class("nav"),
+            TokenType::Class("active"),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    fn attr<'a>(name: &'a str, value: Option<&'a str>, escaped: bool) -> Attribute<'a> {
+        Attribute::new(name, value.map(|value| value.into()), escaped)
+    }
+
+    #[test]
+    fn attrs() {
+        let src = "(lang=\"en\", class=foo ? 'a' : 'b', checked, data-x=[1,2]) p";
+        let mut true1 = Lexer::new(src);
+        let mut false1 = Lexer::new("p(lang=\"en\")");
+        assert_eq!(true1.attrs(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Attrs(vec![
+                attr("lang", Some("\"en\""), true),
+                attr("class", Some("foo ? 'a' : 'b'"), true),
+                attr("checked", None, true),
+                attr("data-x", Some("[1,2]"), true)
+            ])),
+            src.len() - 2,
+            0
+        ))));
+        assert_eq!(false1.attrs(), Ok(None));
+    }
+
+    #[test]
+    fn attrs_separators() {
+        let src = "(\n  href='/' title=title\n  x = a + b 'data-y'!=\"<b>\"\n  disabled\n)";
+        let mut ls = Lexer::new(src);
+        assert_eq!(ls.attrs(), Ok(Some(TokenResult::new(
+            ls.tok(TokenType::Attrs(vec![
+                attr("href", Some("'/'"), true),
+                attr("title", Some("title"), true),
+                attr("x", Some("a + b"), true),
+                attr("data-y", Some("\"<b>\""), false),
+                attr("disabled", None, true)
+            ])),
+            src.len(),
+            4
+        ))));
+    }
+
+    #[test]
+    fn attrs_unterminated() {
+        let mut ls = Lexer::new("(lang=\"en\"");
+        let err = ls.attrs().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnterminatedBracket);
+        assert_eq!(err.message, "unterminated bracket, expected ')'");
+    }
+
+    #[test]
+    fn attrs_mismatched() {
+        let mut ls = Lexer::new("(lang=\"en\"]");
+        let err = ls.attrs().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnterminatedBracket);
+        assert_eq!(err.message, "expected ')' to close bracketed expression");
+    }
+
+    #[test]
+    fn attrs_invalid() {
+        let mut ls = Lexer::new("p(=\"x\")");
+        let err = lex_error(ls);
+        assert_eq!(err.kind, LexErrorKind::InvalidAttribute);
+        assert_eq!(err.message, "attribute value without a name");
+        assert_eq!((err.span.start.column, err.span.end.column), (2, 8));
+
+        ls = Lexer::new("(a=, b)");
+        assert_eq!(ls.attrs().unwrap_err().message, "missing value for attribute \"a\"");
+        ls = Lexer::new("(a !=)");
+        assert_eq!(ls.attrs().unwrap_err().message, "missing value for attribute \"a\"");
+        ls = Lexer::new("(a=fn (x))");
+        let err = ls.attrs().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::InvalidAttribute);
+        assert_eq!(err.message, "invalid attribute name \"(x)\"");
+    }
+
+    #[test]
+    fn attrs_keyword_operators() {
+        let src = "(a=typeof b, c=x instanceof y d='k' in o e=this f=new Date() g)";
+        let mut ls = Lexer::new(src);
+        assert_eq!(ls.attrs(), Ok(Some(TokenResult::new(
+            ls.tok(TokenType::Attrs(vec![
+                attr("a", Some("typeof b"), true),
+                attr("c", Some("x instanceof y"), true),
+                attr("d", Some("'k' in o"), true),
+                attr("e", Some("this"), true),
+                attr("f", Some("new Date()"), true),
+                attr("g", None, true)
+            ])),
+            src.len(),
+            0
+        ))));
+        // quoted names may be anything
+        assert_eq!(token_types("a('(click)'='go()' '[x]')")[1], TokenType::Attrs(vec![
+            attr("(click)", Some("'go()'"), true),
+            attr("[x]", None, true)
+        ]));
+    }
+
+    #[test]
+    fn attributes_block() {
+        let mut true1 = Lexer::new("&attributes({'data-foo': foo(1, 2)}) p");
+        let mut false1 = Lexer::new("&attrs(foo)");
+        assert_eq!(true1.attributes_block(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::AndAttributes("{'data-foo': foo(1, 2)}")),
+            "&attributes({'data-foo': foo(1, 2)})".len(),
+            0
+        ))));
+        assert_eq!(false1.attributes_block(), Ok(None));
+
+        let tokens = token_types("a.btn(href='/')&attributes(attributes)");
+        assert_eq!(tokens, vec![
+            TokenType::Tag("a"),
+            TokenType::Class("btn"),
+            TokenType::Attrs(vec![attr("href", Some("'/'"), true)]),
+            TokenType::AndAttributes("attributes"),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn attributes_block_without_brackets() {
+        let mut ls = Lexer::new("&attributes obj");
+        let err = ls.attributes_block().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnexpectedCharacter);
+        assert_eq!(err.message, "unrecognised start of bracketed expression");
+    }
+
+    #[test]
+    fn slash() {
+        let mut true1 = Lexer::new("/");
+        let mut false1 = Lexer::new("img");
+        assert_eq!(true1.slash(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Slash), 1, 0
+        ))));
+        assert_eq!(false1.slash(), Ok(None));
+    }
+
+    #[test]
+    fn colon() {
+        let mut true1 = Lexer::new(":  a");
+        let mut false1 = Lexer::new("a:");
+        assert_eq!(true1.colon(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Colon), 3, 0
+        ))));
+        assert_eq!(false1.colon(), Ok(None));
+    }
+
+    #[test]
+    fn tags() {
+        let tokens = token_types("ul\n  li: a\n  img(src='x')/");
+        assert_eq!(tokens, vec![
+            TokenType::Tag("ul"),
+            TokenType::Indent,
+            TokenType::Tag("li"),
+            TokenType::Colon,
+            TokenType::Tag("a"),
+            TokenType::Newline,
+            TokenType::Tag("img"),
+            TokenType::Attrs(vec![attr("src", Some("'x'"), true)]),
+            TokenType::Slash,
+            TokenType::Outdent,
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn text() {
+        let mut true1 = Lexer::new(" Jade - node template engine\n");
+        let mut true2 = Lexer::new("| piped text");
+        let mut true3 = Lexer::new("|\n");
+        let mut false1 = Lexer::new("p text");
+        assert_eq!(true1.text(), Ok(Some(TokenResult::new(
+            true1.tok_at(TokenType::Text("Jade - node template engine".into()), 1, 27), 28, 0
+        ))));
+        assert_eq!(true2.text(), Ok(Some(TokenResult::new(
+            true2.tok_at(TokenType::Text("piped text".into()), 2, 10), 12, 0
+        ))));
+        assert_eq!(true3.text(), Ok(Some(TokenResult::new(
+            true3.tok_at(TokenType::Text("".into()), 1, 0), 1, 0
+        ))));
+        assert_eq!(false1.text(), Ok(None));
+    }
+
+    #[test]
+    fn text_trailing_space() {
+        let mut true1 = Lexer::new(" \ndiv");
+        assert_eq!(true1.text(), Ok(Some(TokenResult::new(
+            true1.tok_at(TokenType::Text("".into()), 1, 0), 1, 0
+        ))));
+        for src in ["p \ndiv", "div(a=1) \ndiv", "#a \r\ndiv"].iter() {
+            let tokens = token_types(src);
+            assert_eq!(&tokens[tokens.len() - 4..], &[
+                TokenType::Text("".into()),
+                TokenType::Newline,
+                TokenType::Tag("div"),
+                TokenType::EndOfSource
+            ], "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn doctype() {
+        let mut true1 = Lexer::new("doctype html\nhtml");
+        let mut true2 = Lexer::new("doctype\n");
+        let mut true3 = Lexer::new("doctype html PUBLIC \"-//W3C//DTD XHTML Basic 1.1//EN\"");
+        let mut false1 = Lexer::new("doctypes");
+        assert_eq!(true1.doctype(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Doctype("html")), 12, 0
+        ))));
+        assert_eq!(true2.doctype(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::Doctype("default")), 7, 0
+        ))));
+        assert_eq!(true3.doctype(), Ok(Some(TokenResult::new(
+            true3.tok(TokenType::Doctype(
+                "html PUBLIC \"-//W3C//DTD XHTML Basic 1.1//EN\""
+            )),
+            53,
+            0
+        ))));
+        assert_eq!(false1.doctype(), Ok(None));
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut true1 = Lexer::new("#{'h' + level} Title");
+        let mut false1 = Lexer::new("#title");
+        assert_eq!(true1.interpolation(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::InterpolatedTag("'h' + level")),
+            14,
+            0
+        ))));
+        assert_eq!(false1.interpolation(), Ok(None));
+
+        // only a tag at the start of a line, not in piped text
+        assert_eq!(token_types("div\n| #{a}\n#{a}"), vec![
+            TokenType::Tag("div"),
+            TokenType::Newline,
+            TokenType::Interpolation { expr: "a", escaped: true },
+            TokenType::Newline,
+            TokenType::InterpolatedTag("a"),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn text_interpolation() {
+        let src = "p Hi #{ {a: name}.a }, !{html}\\#{not} #{\"}\"}";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Tag("p"),
+            TokenType::Text("Hi ".into()),
+            TokenType::Interpolation { expr: "{a: name}.a", escaped: true },
+            TokenType::Text(", ".into()),
+            TokenType::Interpolation { expr: "html", escaped: false },
+            TokenType::Text("#{not} ".into()),
+            TokenType::Interpolation { expr: "\"}\"", escaped: true },
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn tag_interpolation() {
+        let src = "p This is #[em(class=\"x]\") very #[b] ] important \\#[not]";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Tag("p"),
+            TokenType::Text("This is ".into()),
+            TokenType::StartTagInterpolation,
+            TokenType::Tag("em"),
+            TokenType::Attrs(vec![attr("class", Some("\"x]\""), true)]),
+            TokenType::Text("very ".into()),
+            TokenType::StartTagInterpolation,
+            TokenType::Tag("b"),
+            TokenType::EndTagInterpolation,
+            TokenType::Text(" ".into()),
+            TokenType::EndTagInterpolation,
+            TokenType::Text(" important #[not]".into()),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn tag_interpolation_unterminated() {
+        let err = lex_error(Lexer::new("p Hi #[em there"));
+        assert_eq!(err.kind, LexErrorKind::UnterminatedBracket);
+        assert_eq!(err.message, "unterminated interpolation, expected ']'");
+    }
+
+    #[test]
+    fn borrowed_payloads() {
+        let src = "a(href='/x', title=\"#{t}\") Hi \\#{not}, #{name}\n-\n  x\n  y";
+        let tokens = token_types(src);
+        let owned = |cow: &Cow<str>| match *cow { Cow::Owned(_) => true, Cow::Borrowed(_) => false };
+        match tokens[1] {
+            TokenType::Attrs(ref attrs) => {
+                assert!(!owned(attrs[0].value.as_ref().unwrap()));
+                // rewriting the interpolation needs a new string
+                assert!(owned(attrs[1].value.as_ref().unwrap()));
+            },
+            ref tok => panic!("expected attributes, found {:?}", tok)
+        }
+        // removing the escape joins two slices of the input
+        assert_eq!(tokens[2], TokenType::Text("Hi #{not}, ".into()));
+        assert!(match tokens[2] { TokenType::Text(ref text) => owned(text), _ => false });
+        assert!(match tokens[5] { TokenType::Code { ref expr, .. } => owned(expr), _ => false });
+
+        let tokens = token_types("p Hi #{name}!");
+        assert!(match tokens[1] { TokenType::Text(ref text) => !owned(text), _ => false });
+        assert!(match tokens[3] { TokenType::Text(ref text) => !owned(text), _ => false });
+    }
+
+    #[test]
+    fn text_interpolation_unterminated() {
+        let err = lex_error(Lexer::new("p Hi #{name"));
+        assert_eq!(err.kind, LexErrorKind::UnterminatedBracket);
+        assert_eq!(err.message, "unterminated interpolation, expected '}'");
+        assert_eq!(err.snippet, "p Hi #{name");
+    }
+
+    #[test]
+    fn attribute_interpolation() {
+        let src = "(href=\"/user/#{id}\", title='\\#{x}', alt=a + \"#{b}\")";
+        let mut ls = Lexer::new(src);
+        assert_eq!(ls.attrs(), Ok(Some(TokenResult::new(
+            ls.tok(TokenType::Attrs(vec![
+                attr("href", Some("\"/user/\" + (id) + \"\""), true),
+                attr("title", Some("'\\#{x}'"), true),
+                attr("alt", Some("a + \"#{b}\""), true)
+            ])),
+            src.len(),
+            0
+        ))));
+    }
+
+    #[test]
+    fn text_html() {
+        let mut true1 = Lexer::new("<em>html</em>\n");
+        let mut false1 = Lexer::new("em html");
+        assert_eq!(true1.text_html(), Ok(Some(TokenResult::new(
+            true1.tok_at(TokenType::Text("<em>html</em>".into()), 0, 13), 13, 0
+        ))));
+        assert_eq!(false1.text_html(), Ok(None));
+    }
+
+    #[test]
+    fn dot() {
+        let mut true1 = Lexer::new(". \n  text");
+        let mut false1 = Lexer::new(".col");
+        assert_eq!(true1.dot(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::PipelessText), 2, 0
+        ))));
+        assert!(true1.pipeless);
+        assert_eq!(false1.dot(), Ok(None));
+        assert!(!false1.pipeless);
+    }
+
+    #[test]
+    fn pipeless_text() {
+        let src = "div\n  p.\n    if (a) {\n      b()\n\n    }\n  p\n\n  | piped";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Tag("div"),
+            TokenType::Indent,
+            TokenType::Tag("p"),
+            TokenType::PipelessText,
+            TokenType::Indent,
+            TokenType::Text("if (a) {".into()),
+            TokenType::Newline,
+            TokenType::Text("  b()".into()),
+            TokenType::Text("".into()),
+            TokenType::Newline,
+            TokenType::Text("}".into()),
+            TokenType::Outdent,
+            TokenType::Tag("p"),
+            TokenType::Newline,
+            TokenType::Text("piped".into()),
+            TokenType::Outdent,
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn comment_block() {
+        let src = "// comment\n  body\n\np";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Comment(Some(" comment"), true),
+            TokenType::Indent,
+            TokenType::Text("body".into()),
+            TokenType::Outdent,
+            TokenType::Tag("p"),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn indent() {
+        let mut true1 = Lexer::new("\n    p");
+        let mut true2 = Lexer::new("\np");
+        let mut false1 = Lexer::new("p\n");
+        assert_eq!(true1.indent(), Ok(Some(TokenResult::new(
+            true1.tok(TokenType::Indent), 5, 1
+        ))));
+        assert_eq!(true1.last_indents, 4);
+        assert_eq!(true1.indent_stack, vec![4]);
+        assert_eq!(true1.indent_char, Some(' '));
+        assert_eq!(true2.indent(), Ok(Some(TokenResult::new(
+            true2.tok(TokenType::Newline), 1, 1
+        ))));
+        assert_eq!(false1.indent(), Ok(None));
+    }
+
+    #[test]
+    fn indents() {
+        let src = "html\n\thead\n\t\ttitle\n\n\tbody\n\t\tdiv\n\t\t\tp\nfooter\n\tp";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Tag("html"),
+            TokenType::Indent,
+            TokenType::Tag("head"),
+            TokenType::Indent,
+            TokenType::Tag("title"),
+            TokenType::Outdent,
+            TokenType::Tag("body"),
+            TokenType::Indent,
+            TokenType::Tag("div"),
+            TokenType::Indent,
+            TokenType::Tag("p"),
+            TokenType::Outdent,
+            TokenType::Outdent,
+            TokenType::Outdent,
+            TokenType::Tag("footer"),
+            TokenType::Indent,
+            TokenType::Tag("p"),
+            TokenType::Outdent,
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn indent_mixed() {
+        let src = "ul\n  li\n  \tli";
+        let err = lex_error(Lexer::new_with_filename(src, "test.jade".to_string()));
+        assert_eq!(err.kind, LexErrorKind::BadIndentation);
+        assert_eq!(err.to_string(), "test.jade:3:3: invalid indentation, found a tab in indentation using spaces");
+        assert_eq!(err.snippet, "  \tli");
+    }
+
+    #[test]
+    fn indent_inconsistent() {
+        let err = lex_error(Lexer::new("ul\n\tli\n  li"));
+        assert_eq!(err.kind, LexErrorKind::BadIndentation);
+        assert_eq!(err.to_string(), "line 3, column 1: invalid indentation, found a space in indentation using tabs");
+    }
+
+    #[test]
+    fn outdent_inconsistent() {
+        let err = lex_error(Lexer::new("ul\n    li\n      a\n  li"));
+        assert_eq!(err.kind, LexErrorKind::BadIndentation);
+        assert_eq!(err.to_string(), "line 4, column 1: inconsistent indentation, expected 0 or 4 spaces but found 2");
+        assert_eq!((err.span.start.offset, err.span.end.offset), (18, 20));
+    }
+
+    #[test]
+    fn next() {
+        let mut ls = Lexer::new("\n\n// first\n//- second\n");
+        assert_eq!(kind(&ls.next().unwrap()), (&TokenType::Newline, 2));
+        assert_eq!(kind(&ls.next().unwrap()), (
+            &TokenType::Comment(Some(" first"), true), 3
+        ));
+        assert_eq!(kind(&ls.next().unwrap()), (&TokenType::Newline, 3));
+        assert_eq!(kind(&ls.next().unwrap()), (
+            &TokenType::Comment(Some(" second"), false), 4
+        ));
+        assert_eq!(kind(&ls.next().unwrap()), (&TokenType::Newline, 4));
+        assert_eq!(kind(&ls.next().unwrap()), (&TokenType::EndOfSource, 5));
+        // keep returning end of source
+        assert_eq!(kind(&ls.next().unwrap()), (&TokenType::EndOfSource, 5));
+    }
+
+    #[test]
+    fn iterator() {
+        let tokens: Vec<Token> = Lexer::new("// one\n").collect::<Result<_, _>>().unwrap();
+        assert_eq!(tokens.iter().map(kind).collect::<Vec<_>>(), vec![
+            (&TokenType::Comment(Some(" one"), true), 1),
+            (&TokenType::Newline, 1),
+            (&TokenType::EndOfSource, 2)
+        ]);
+
+        let mut ls = Lexer::new("// one\n// two");
+        assert_eq!(kind(ls.lookahead(3).unwrap()), (
+            &TokenType::Comment(Some(" two"), true), 2
+        ));
+        let comments = ls.by_ref()
+            .map(|tok| tok.unwrap())
+            .take_while(|tok| tok.token_type != TokenType::EndOfSource)
+            .filter(|tok| tok.token_type != TokenType::Newline)
+            .count();
+        assert_eq!(comments, 2);
+        assert_eq!(Iterator::next(&mut ls), None);
+    }
+
+    #[test]
+    fn deferred() {
+        let mut ls = Lexer::new("");
+        ls.defer(Token::new(TokenType::Indent, 1));
+        ls.defer(Token::new(TokenType::Outdent, 1));
+        assert_eq!(ls.next(), Ok(Token::new(TokenType::Indent, 1)));
+        assert_eq!(ls.next(), Ok(Token::new(TokenType::Outdent, 1)));
+        assert_eq!(kind(&ls.next().unwrap()), (&TokenType::EndOfSource, 1));
+    }
+
+    #[test]
+    fn lex_recovering() {
+        let src = "ul\n  li(=x) a\n    b\n   li c\n  li #{d\n\t\tp\n  li e";
+        let (tokens, errors) = Lexer::new(src).lex_recovering();
+        let tokens: Vec<&TokenType> = tokens.iter().map(|tok| tok.token_type()).collect();
+        assert_eq!(tokens, vec![
+            &TokenType::Tag("ul"),
+            &TokenType::Indent,
+            &TokenType::Tag("li"),
+            // the rest of the line is skipped
+            &TokenType::Indent,
+            &TokenType::Tag("b"),
+            // as is the line with bad indentation
+            &TokenType::Outdent,
+            &TokenType::Tag("li"),
+            // and the line after the bad interpolation, which is also badly indented
+            &TokenType::Newline,
+            &TokenType::Tag("li"),
+            &TokenType::Text("e".into()),
+            &TokenType::Outdent,
+            &TokenType::EndOfSource
+        ]);
+        let errors: Vec<(LexErrorKind, u32)> = errors.iter()
+            .map(|err| (err.kind, err.span.start.line))
+            .collect();
+        assert_eq!(errors, vec![
+            (LexErrorKind::InvalidAttribute, 2),
+            (LexErrorKind::BadIndentation, 4),
+            (LexErrorKind::UnterminatedBracket, 5)
+        ]);
+    }
+
+    #[test]
+    fn spans() {
+        let at = |offset, line, column| Position { offset, line, column };
+        let tokens: Vec<Token> = Lexer::new("p héllo #{a}\n  i").collect::<Result<_, _>>().unwrap();
+        let spans: Vec<_> = tokens.iter().map(|tok| (tok.token_type(), tok.span())).collect();
+        assert_eq!(spans, vec![
+            (&TokenType::Tag("p"), Span { start: at(0, 1, 1), end: at(1, 1, 2) }),
+            (&TokenType::Text("héllo ".into()), Span { start: at(2, 1, 3), end: at(9, 1, 9) }),
+            (&TokenType::Interpolation { expr: "a", escaped: true }, Span { start: at(9, 1, 9), end: at(13, 1, 13) }),
+            (&TokenType::Indent, Span { start: at(13, 1, 13), end: at(16, 2, 3) }),
+            (&TokenType::Tag("i"), Span { start: at(16, 2, 3), end: at(17, 2, 4) }),
+            (&TokenType::Outdent, Span { start: at(17, 2, 4), end: at(17, 2, 4) }),
+            (&TokenType::EndOfSource, Span { start: at(17, 2, 4), end: at(17, 2, 4) })
+        ]);
+        assert_eq!(tokens[3].line_number(), 1);
+
+        // tokens in a tag interpolation, and its errors, are placed in the
+        // outer line
+        let tokens: Vec<Token> = Lexer::new("p\n| ä \\#{b} #[em.c d] é").collect::<Result<_, _>>().unwrap();
+        let spans: Vec<_> = tokens[2..].iter().map(|tok| (tok.token_type(), tok.span())).collect();
+        assert_eq!(spans, vec![
+            (&TokenType::Text("ä #{b} ".into()), Span { start: at(4, 2, 3), end: at(13, 2, 11) }),
+            (&TokenType::StartTagInterpolation, Span { start: at(13, 2, 11), end: at(15, 2, 13) }),
+            (&TokenType::Tag("em"), Span { start: at(15, 2, 13), end: at(17, 2, 15) }),
+            (&TokenType::Class("c"), Span { start: at(17, 2, 15), end: at(19, 2, 17) }),
+            (&TokenType::Text("d".into()), Span { start: at(20, 2, 18), end: at(21, 2, 19) }),
+            (&TokenType::EndTagInterpolation, Span { start: at(21, 2, 19), end: at(22, 2, 20) }),
+            (&TokenType::Text(" é".into()), Span { start: at(22, 2, 20), end: at(25, 2, 22) }),
+            (&TokenType::EndOfSource, Span { start: at(25, 2, 22), end: at(25, 2, 22) })
+        ]);
+        let err = lex_error(Lexer::new("p\n| a #[em(b] c"));
+        assert_eq!(err.kind, LexErrorKind::UnterminatedBracket);
+        assert_eq!(err.span, Span { start: at(10, 2, 9), end: at(11, 2, 10) });
+        assert_eq!(err.snippet, "| a #[em(b] c");
+    }
+
+    #[test]
+    fn multibyte_text() {
+        let src = "p Grüße, #{名前}!\n  | 日本語のテキスト #[em 強調]";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Tag("p"),
+            TokenType::Text("Grüße, ".into()),
+            TokenType::Interpolation { expr: "名前", escaped: true },
+            TokenType::Text("!".into()),
+            TokenType::Indent,
+            TokenType::Text("日本語のテキスト ".into()),
+            TokenType::StartTagInterpolation,
+            TokenType::Tag("em"),
+            TokenType::Text("強調".into()),
+            TokenType::EndTagInterpolation,
+            TokenType::Outdent,
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn multibyte_attributes() {
+        let src = "a(title=\"Grüße\", data-名前='日本', alt=\"#{ä}ö\") Überschrift";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Tag("a"),
+            TokenType::Attrs(vec![
+                attr("title", Some("\"Grüße\""), true),
+                attr("data-名前", Some("'日本'"), true),
+                attr("alt", Some("\"\" + (ä) + \"ö\""), true)
+            ]),
+            TokenType::Text("Überschrift".into()),
+            TokenType::EndOfSource
+        ]);
+    }
+
+    #[test]
+    fn multibyte_identifiers() {
+        let src = "größe#überblick.x\nmixin grüß(ä)\n+grüß('ö')";
+        let tokens = token_types(src);
+        assert_eq!(tokens, vec![
+            TokenType::Tag("größe"),
+            TokenType::Id("überblick"),
+            TokenType::Class("x"),
+            TokenType::Newline,
+            TokenType::Mixin { name: "grüß", params: vec!["ä"] },
+            TokenType::Newline,
+            TokenType::Call { name: "grüß", args: vec!["'ö'"] },
+            TokenType::EndOfSource
+        ]);
+
+        assert_eq!(token_types("p.größe.ä._1"), vec![
+            TokenType::Tag("p"),
+            TokenType::Class("größe"),
+            TokenType::Class("ä"),
+            TokenType::Class("_1"),
+            TokenType::EndOfSource
+        ]);
+        let err = lex_error(Lexer::new(".1größe"));
+        assert_eq!(err.kind, LexErrorKind::InvalidAttribute);
+        assert_eq!(err.message, "invalid class name \".1größe\"");
+
+        let err = lex_error(Lexer::new("p Grüße\nä.1foo"));
+        assert_eq!(err.span.start, Position { offset: 12, line: 2, column: 2 });
+        assert_eq!(err.snippet, "ä.1foo");
+    }
+
+    #[test]
+    fn byte_order_mark() {
+        let tokens: Vec<Token> = Lexer::new("\u{feff}doctype html\np").collect::<Result<_, _>>().unwrap();
+        assert_eq!(tokens[0].token_type(), &TokenType::Doctype("html"));
+        assert_eq!(tokens[0].span().start, Position { offset: 3, line: 1, column: 1 });
+        assert_eq!(tokens[2].token_type(), &TokenType::Tag("p"));
+
+        let err = lex_error(Lexer::new("\u{feff}.1foo"));
+        assert_eq!(err.span.start, Position { offset: 3, line: 1, column: 1 });
+        assert_eq!(err.snippet, ".1foo");
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let src = concat!(
+            "doctype html\r\n",
+            "html\r\n",
+            "  // note\r\n",
+            "  body(a=1,\r\n",
+            "    b=2)\r\n",
+            "    p.\r\n",
+            "      text\r\n",
+            "        more\r\n",
+            "\r\n",
+            "    p Hi #{x}\r\n",
+            "    | piped\r\n",
+            "    block\r\n",
+            "    -\r\n",
+            "      x = 1\r\n",
+            "      y = 2\r\n");
+        let lf = src.replace("\r\n", "\n");
+        let lex = |src| -> Vec<(TokenType, u32)> {
+            Lexer::new(src).map(|tok| tok.unwrap()).map(|tok| (tok.token_type, tok.line_number)).collect()
+        };
+        let tokens = lex(src);
+        assert_eq!(tokens, lex(&lf));
+        assert!(tokens.contains(&(TokenType::Comment(Some(" note"), true), 3)));
+        assert!(tokens.contains(&(code(false, false, "x = 1\ny = 2"), 13)));
+
+        // errors point at the same place as with `\n`
+        for src in ["ul\r\n    li\r\n      a\r\n  li", "ul\r\n  li\r\n  \tli"].iter() {
+            let err = lex_error(Lexer::new(src));
+            let lf_err = lex_error(Lexer::new(&src.replace("\r\n", "\n")));
+            assert_eq!(err.kind, LexErrorKind::BadIndentation);
+            assert_eq!(err.to_string(), lf_err.to_string());
+            assert_eq!(err.snippet, lf_err.snippet);
+        }
+        let err = lex_error(Lexer::new("ul\r\n  li\r\n  \tli"));
+        assert_eq!(err.to_string(), "line 3, column 3: invalid indentation, found a tab in indentation using spaces");
+    }
+
+    #[test]
+    fn carriage_return_at_end() {
+        assert_eq!(token_types("// x\r"), vec![
+            TokenType::Comment(Some(" x"), true),
+            TokenType::EndOfSource
+        ]);
+        assert_eq!(token_types("p.\n  a\r"), vec![
+            TokenType::Tag("p"),
+            TokenType::PipelessText,
+            TokenType::Indent,
+            TokenType::Text("a".into()),
+            TokenType::Outdent,
+            TokenType::EndOfSource
+        ]);
+        let (tokens, errors) = Lexer::new("p.\n  a\r").lex_recovering();
+        assert_eq!(tokens.len(), 6);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn fail() {
+        let mut ls = Lexer::new("\u{0}\u{0}");
+        let err = ls.next().unwrap_err();
+        assert_eq!(err.kind, LexErrorKind::UnexpectedCharacter);
+        assert_eq!(err.message, "unexpected text \"\\0\\0\"");
+        // the input isn't consumed, so the error repeats
+        assert_eq!(ls.next(), Err(err));
+        // but the iterator stops after it
+        assert!(Iterator::next(&mut ls).unwrap().is_err());
+        assert_eq!(Iterator::next(&mut ls), None);
     }
 
     #[test]
     fn complex() {
+        assert_eq!(token_types(jade_block()), vec![
+            TokenType::Doctype("html"),
+            TokenType::Newline,
+            TokenType::Tag("html"),
+            TokenType::Attrs(vec![attr("lang", Some("\"en\""), true)]),
+            TokenType::Indent,
+            TokenType::Tag("head"),
+            TokenType::Indent,
+            TokenType::Tag("title"),
+            code(true, true, "pageTitle"),
+            TokenType::Newline,
+            TokenType::Tag("script"),
+            TokenType::Attrs(vec![attr("type", Some("'text/javascript'"), true)]),
+            TokenType::PipelessText,
+            TokenType::Indent,
+            TokenType::Text("if (foo) {".into()),
+            TokenType::Newline,
+            TokenType::Text("   bar(1 + 5)".into()),
+            TokenType::Newline,
+            TokenType::Text("}".into()),
+            TokenType::Outdent,
+            TokenType::Outdent,
+            TokenType::Tag("body"),
+            TokenType::Indent,
+            TokenType::Tag("h1"),
+            TokenType::Text("Jade - node template engine".into()),
+            TokenType::Newline,
+            TokenType::Id("container"),
+            TokenType::Class("col"),
+            TokenType::Indent,
+            TokenType::If("youAreUsingJade"),
+            TokenType::Indent,
+            TokenType::Tag("p"),
+            TokenType::Text("You are amazing".into()),
+            TokenType::Outdent,
+            TokenType::Else,
+            TokenType::Indent,
+            TokenType::Tag("p"),
+            TokenType::Text("Get on it!".into()),
+            TokenType::Outdent,
+            TokenType::Tag("p"),
+            TokenType::PipelessText,
+            TokenType::Indent,
+            TokenType::Text("Jade is a terse and simple".into()),
+            TokenType::Newline,
+            TokenType::Text("templating language with a".into()),
+            TokenType::Newline,
+            TokenType::Text("strong focus on performance".into()),
+            TokenType::Newline,
+            TokenType::Text("and powerful features.".into()),
+            TokenType::Newline,
+            TokenType::Outdent,
+            TokenType::Outdent,
+            TokenType::Outdent,
+            TokenType::Outdent,
+            TokenType::EndOfSource
+        ]);
     }
 }
@@ -6,13 +6,17 @@
 extern crate regex;
 
 // This rewrites the regex! macro while compiler
-// extensions are not in stable
+// extensions are not in stable. Each regex is compiled once, on first use
 macro_rules! regex(
-    ($s:expr) => (regex::Regex::new($s).unwrap());
+    ($s:expr) => ({
+        static RE: ::std::sync::OnceLock<regex::Regex> = ::std::sync::OnceLock::new();
+        RE.get_or_init(|| regex::Regex::new($s).unwrap())
+    });
 );
 
 pub mod lexer;
 pub mod brackets;
+pub mod doctypes;
 
 pub fn parse(tpl: String) {
     